@@ -0,0 +1,309 @@
+//! TinyLFU admission: keeps objects that are rarely asked for from displacing popular ones.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use sled::Tree;
+
+use crate::{decode_u64, CreedmoorError, EvictionVictim, Result};
+
+/// Settings for the TinyLFU admission filter, see `CacheConfig::admission_filter`.
+///
+/// Every `get` and `put` counts towards the frequency of its key. A put of a new key that
+/// would evict objects is rejected with `CreedmoorError::NotAdmitted` if the key is estimated
+/// to be asked for less often than any of those objects, so it isn't written to disk only to
+/// be evicted again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TinyLfu {
+    /// Number of distinct keys the sketch is sized for, rounded up to a power of two. A bigger
+    /// sketch estimates more accurately and costs 5 bytes per rounded up key, in memory and on
+    /// disk, so 81,920 bytes for the default.
+    pub expected_objects: usize,
+    /// Save the sketch to sled after this many accesses. It is also saved when the last clone
+    /// of the cache is dropped.
+    pub persist_every: u64,
+}
+
+impl Default for TinyLfu {
+    fn default() -> Self {
+        Self { expected_objects: 10_000, persist_every: 1_000 }
+    }
+}
+
+impl TinyLfu {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.expected_objects == 0 || self.persist_every == 0 {
+            return Err(CreedmoorError::InvalidConfig(format!(
+                "TinyLfu expected_objects and persist_every must be at least 1, got {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Count-min sketch of counters up to 15, one byte each, behind a doorkeeper bloom filter of
+/// one byte per counter column.
+///
+/// A key's first access only sets its doorkeeper bits, so one-off keys never reach the
+/// counters. Once the sketch has recorded ten accesses per counter column, every counter is
+/// halved and the doorkeeper cleared, so old popularity fades.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrequencySketch {
+    width: usize,
+    /// `DEPTH` rows of `width` counters, one per byte
+    counters: Vec<u8>,
+    doorkeeper: Vec<u64>,
+    /// Accesses recorded since the last halving
+    additions: u64,
+}
+
+impl FrequencySketch {
+    const DEPTH: usize = 4;
+    const MAX_COUNT: u8 = 15;
+    /// Accesses per counter column between halvings
+    const SAMPLE_FACTOR: u64 = 10;
+
+    /// A sketch for about `expected_objects` distinct keys.
+    pub fn new(expected_objects: usize) -> Self {
+        let width = expected_objects.clamp(16, 1 << 30).next_power_of_two();
+        Self {
+            width,
+            counters: vec![0; Self::DEPTH * width],
+            doorkeeper: vec![0; width / 8],
+            additions: 0,
+        }
+    }
+
+    /// 64-bit FNV-1a, stable across builds so a saved sketch stays valid.
+    fn hash(key: &[u8]) -> u64 {
+        key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+    }
+
+    /// Index of `key`'s counter in `row`, by double hashing.
+    fn counter(&self, hash: u64, row: usize) -> usize {
+        let (low, high) = (hash as u32 as u64, (hash >> 32) | 1);
+        row * self.width + (low.wrapping_add(high.wrapping_mul(row as u64)) as usize & (self.width - 1))
+    }
+
+    fn doorkeeper_bits(&self, hash: u64) -> [usize; 2] {
+        let bits = self.doorkeeper.len() * 64;
+        [hash as usize % bits, hash.rotate_left(32) as usize % bits]
+    }
+
+    fn in_doorkeeper(&self, hash: u64) -> bool {
+        self.doorkeeper_bits(hash).iter().all(|&bit| self.doorkeeper[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Count an access to `key`.
+    pub fn increment(&mut self, key: &[u8]) {
+        let hash = Self::hash(key);
+        if self.in_doorkeeper(hash) {
+            for row in 0..Self::DEPTH {
+                let counter = self.counter(hash, row);
+                self.counters[counter] = (self.counters[counter] + 1).min(Self::MAX_COUNT);
+            }
+        } else {
+            for bit in self.doorkeeper_bits(hash) {
+                self.doorkeeper[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        self.additions += 1;
+        if self.additions >= Self::SAMPLE_FACTOR * self.width as u64 {
+            self.halve();
+        }
+    }
+
+    fn halve(&mut self) {
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+        self.doorkeeper.fill(0);
+        self.additions /= 2;
+    }
+
+    /// Estimated number of recent accesses to `key`, at most 16.
+    pub fn estimate(&self, key: &[u8]) -> u8 {
+        let hash = Self::hash(key);
+        let counted = (0..Self::DEPTH).map(|row| self.counters[self.counter(hash, row)]).min().unwrap_or(0);
+        counted + self.in_doorkeeper(hash) as u8
+    }
+
+    /// A sketch for about `expected_objects` keys, starting from `saved` unless it was saved
+    /// for a different number.
+    pub(crate) fn load(saved: Option<&[u8]>, expected_objects: usize) -> Result<Self> {
+        let empty = Self::new(expected_objects);
+        match saved {
+            Some(saved) => Ok(Self::decode(saved, empty.width)?.unwrap_or(empty)),
+            None => Ok(empty),
+        }
+    }
+
+    /// Width, additions, counters and doorkeeper words, all big-endian.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + self.counters.len() + self.doorkeeper.len() * 8);
+        bytes.extend_from_slice(&(self.width as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.additions.to_be_bytes());
+        bytes.extend_from_slice(&self.counters);
+        for word in &self.doorkeeper {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Decode a sketch saved by `encode`, or `None` if it was saved with a different width.
+    fn decode(bytes: &[u8], width: usize) -> Result<Option<Self>> {
+        let corrupt = || CreedmoorError::CorruptMetadata(format!("saved admission sketch is {} bytes", bytes.len()));
+        if bytes.len() < 16 {
+            return Err(corrupt());
+        }
+        if decode_u64(&bytes[..8], "admission sketch width")? != width as u64 {
+            return Ok(None);
+        }
+        let mut sketch = Self::new(width);
+        let (counters, doorkeeper) = bytes[16..].split_at_checked(sketch.counters.len()).ok_or_else(corrupt)?;
+        if doorkeeper.len() != sketch.doorkeeper.len() * 8 {
+            return Err(corrupt());
+        }
+        sketch.additions = decode_u64(&bytes[8..16], "admission sketch additions")?;
+        sketch.counters.copy_from_slice(counters);
+        for (word, bytes) in sketch.doorkeeper.iter_mut().zip(doorkeeper.chunks_exact(8)) {
+            *word = decode_u64(bytes, "admission sketch doorkeeper")?;
+        }
+        Ok(Some(sketch))
+    }
+}
+
+/// The admission filter of one cache, shared by its clones and saved to its own tree.
+pub(crate) struct Admission {
+    sketch: Mutex<FrequencySketch>,
+    tree: Tree,
+    persist_every: u64,
+    /// Accesses recorded since the sketch was last saved
+    unsaved: AtomicU64,
+}
+
+impl Admission {
+    /// Tree holding the saved sketch, next to the cache's own trees.
+    pub(crate) const SKETCH_TREE: &'static [u8] = b"admission_sketch";
+    const SKETCH_KEY: &'static [u8] = b"sketch";
+
+    /// Load the sketch saved in `tree`, or start an empty one if there is none or it was saved
+    /// for a different `expected_objects`.
+    pub(crate) fn load(tree: Tree, config: TinyLfu) -> Result<Self> {
+        let sketch = FrequencySketch::load(tree.get(Self::SKETCH_KEY)?.as_deref(), config.expected_objects)?;
+        Ok(Self {
+            sketch: Mutex::new(sketch),
+            tree,
+            persist_every: config.persist_every,
+            unsaved: AtomicU64::new(0),
+        })
+    }
+
+    fn sketch(&self) -> MutexGuard<'_, FrequencySketch> {
+        // Every change leaves the sketch consistent, so it is usable even if a holder panicked
+        self.sketch.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Count an access to `key`, saving the sketch if enough accesses have built up.
+    pub(crate) fn record(&self, key: &[u8]) -> Result<()> {
+        self.sketch().increment(key);
+        if self.unsaved.fetch_add(1, Ordering::Relaxed) + 1 >= self.persist_every {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// If `key` should not displace `victims`, the estimated frequencies of `key` and of the
+    /// most popular victim.
+    pub(crate) fn rejects(&self, key: &[u8], victims: &[EvictionVictim]) -> Option<(u8, u8)> {
+        let sketch = self.sketch();
+        let candidate = sketch.estimate(key);
+        let victim = victims.iter().map(|victim| sketch.estimate(&victim.key)).max()?;
+        (candidate < victim).then_some((candidate, victim))
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        self.unsaved.store(0, Ordering::Relaxed);
+        let encoded = self.sketch().encode();
+        self.tree.insert(Self::SKETCH_KEY, encoded)?;
+        Ok(())
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        // Nowhere to report a failure from here; accesses since the last save are lost
+        if self.save().is_ok() {
+            let _ = self.tree.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{reopen, TestDir};
+    use crate::CacheConfig;
+
+    #[test]
+    fn test_frequency_sketch() {
+        let mut sketch = FrequencySketch::new(64);
+        assert_eq!(sketch.estimate(b"a"), 0);
+        // The first access only reaches the doorkeeper
+        sketch.increment(b"a");
+        assert_eq!(sketch.estimate(b"a"), 1);
+        for _ in 0..20 {
+            sketch.increment(b"a");
+        }
+        assert_eq!(sketch.estimate(b"a"), 16);
+        assert_eq!(FrequencySketch::decode(&sketch.encode(), 64).unwrap(), Some(sketch.clone()));
+        assert_eq!(FrequencySketch::decode(&sketch.encode(), 128).unwrap(), None);
+        assert!(FrequencySketch::decode(&sketch.encode()[..100], 64).is_err());
+
+        // Enough other accesses halve the counters and clear the doorkeeper
+        for n in 0..10 * 64u32 {
+            sketch.increment(&n.to_be_bytes());
+        }
+        assert!(sketch.estimate(b"a") < 16);
+    }
+
+    #[test]
+    fn test_admission_filter() {
+        let cache = CacheConfig::new(30).temporary(true).admission_filter(TinyLfu::default()).build().unwrap();
+        for key in [b"a", b"b", b"c"] {
+            cache.put(key, &[0; 10]).unwrap();
+            cache.get(key).unwrap();
+        }
+        // A newcomer that was never asked for can't displace a popular object...
+        assert!(matches!(
+            cache.put(b"one-off", &[0; 10]),
+            Err(CreedmoorError::NotAdmitted { candidate: 1, victim: 2 })
+        ));
+        assert!(!cache.contains_key(b"one-off").unwrap());
+        assert!(cache.contains_key(b"a").unwrap());
+        // ...until it has been asked for as often
+        cache.get(b"one-off").unwrap();
+        cache.put(b"one-off", &[0; 10]).unwrap();
+        assert!(!cache.contains_key(b"a").unwrap());
+        // Overwrites and puts that fit are always admitted
+        cache.put(b"b", &[1; 10]).unwrap();
+        assert_eq!(cache.stats().unwrap().rejected_admission, 1);
+    }
+
+    #[test]
+    fn test_admission_sketch_persisted() {
+        let sled_path = TestDir::new();
+        let open = || {
+            let admission = TinyLfu { expected_objects: 100, persist_every: 2 };
+            reopen(|| CacheConfig::new(10).path(&sled_path).admission_filter(admission).build())
+        };
+        let cache = open();
+        cache.put(b"a", &[0; 10]).unwrap();
+        cache.get(b"a").unwrap();
+        drop(cache);
+
+        let cache = open();
+        assert!(matches!(cache.put(b"b", &[0; 10]), Err(CreedmoorError::NotAdmitted { .. })));
+    }
+}
@@ -0,0 +1,104 @@
+//! A `MultiLayerCache` for async code on tokio, behind the `async` cargo feature.
+
+use std::time::Duration;
+
+use sled::IVec;
+use tokio::task;
+
+use crate::{MultiLayerCache, Result};
+
+/// Runs the operations of a `MultiLayerCache` on tokio's blocking pool, so sled transactions,
+/// eviction and flushes don't stall the runtime's worker threads.
+///
+/// Keys and values are copied before being handed to the pool. Must be used from inside a
+/// tokio runtime.
+#[derive(Clone)]
+pub struct AsyncMultiLayerCache {
+    cache: MultiLayerCache,
+}
+
+impl AsyncMultiLayerCache {
+    pub fn new(cache: MultiLayerCache) -> Self {
+        Self { cache }
+    }
+
+    /// The blocking cache underneath.
+    pub fn inner(&self) -> &MultiLayerCache {
+        &self.cache
+    }
+
+    /// Run `f` with a clone of the cache on the blocking pool.
+    async fn blocking<T: Send + 'static>(&self, f: impl FnOnce(MultiLayerCache) -> Result<T> + Send + 'static) -> Result<T> {
+        let cache = self.cache.clone();
+        task::spawn_blocking(move || f(cache)).await?
+    }
+
+    /// See `MultiLayerCache::get`.
+    pub async fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        let key = key.to_vec();
+        self.blocking(move |cache| cache.get(&key)).await
+    }
+
+    /// See `MultiLayerCache::put`.
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.blocking(move |cache| cache.put(&key, &value)).await
+    }
+
+    /// See `MultiLayerCache::put_with_ttl`.
+    pub async fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.blocking(move |cache| cache.put_with_ttl(&key, &value, ttl)).await
+    }
+
+    /// See `MultiLayerCache::remove`.
+    pub async fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+        let key = key.to_vec();
+        self.blocking(move |cache| cache.remove(&key)).await
+    }
+
+    /// Flush sled's dirty pages to disk without blocking a thread. Returns the number of bytes
+    /// flushed.
+    pub async fn flush_async(&self) -> Result<usize> {
+        Ok(self.cache.db.flush_async().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Builder;
+
+    #[test]
+    fn test_async_cache() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let cache = AsyncMultiLayerCache::new(MultiLayerCache::temporary(1024, 1024).unwrap());
+            cache.put(b"key", b"value").await.unwrap();
+            assert_eq!(cache.get(b"key").await.unwrap().unwrap(), b"value");
+            cache.put_with_ttl(b"short", b"value", Duration::from_millis(1)).await.unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+            assert_eq!(cache.get(b"short").await.unwrap(), None);
+            assert!(cache.flush_async().await.is_ok());
+            assert_eq!(cache.remove(b"key").await.unwrap().unwrap(), b"value");
+            assert_eq!(cache.get(b"key").await.unwrap(), None);
+            assert_eq!(cache.inner().logical_disk_usage().unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn test_async_concurrent_put() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let cache = AsyncMultiLayerCache::new(MultiLayerCache::temporary(1024, 100).unwrap());
+            let puts = (0..20u8).map(|n| {
+                let cache = cache.clone();
+                tokio::spawn(async move { cache.put(&[n], &[n; 10]).await })
+            });
+            for put in puts.collect::<Vec<_>>() {
+                put.await.unwrap().unwrap();
+            }
+            assert_eq!(cache.inner().logical_disk_usage().unwrap(), 100);
+        });
+    }
+}
@@ -0,0 +1,84 @@
+//! Codecs that turn keys and values into the bytes stored by `MultiLayerCache`.
+//!
+//! `Raw` is always available. The serde based codecs are behind the `bincode`, `json` and
+//! `postcard` cargo features.
+
+use crate::{CreedmoorError, Result};
+
+/// Encodes values of type `T` to bytes and decodes them back.
+pub trait Codec<T> {
+    fn encode(value: &T) -> Result<Vec<u8>>;
+    fn decode(bytes: &[u8]) -> Result<T>;
+}
+
+/// Stores byte strings and UTF-8 strings as-is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(value: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.clone())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Codec<String> for Raw {
+    fn encode(value: &String) -> Result<Vec<u8>> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        String::from_utf8(bytes.to_vec()).map_err(|error| CreedmoorError::Codec(Box::new(error)))
+    }
+}
+
+/// serde + bincode 1.x.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Bincode {
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|error| CreedmoorError::Codec(error))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|error| CreedmoorError::Codec(error))
+    }
+}
+
+/// serde + serde_json.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Json {
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|error| CreedmoorError::Codec(Box::new(error)))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|error| CreedmoorError::Codec(Box::new(error)))
+    }
+}
+
+/// serde + postcard.
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Postcard {
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        postcard::to_allocvec(value).map_err(|error| CreedmoorError::Codec(Box::new(error)))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        postcard::from_bytes(bytes).map_err(|error| CreedmoorError::Codec(Box::new(error)))
+    }
+}
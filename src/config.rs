@@ -0,0 +1,369 @@
+//! Builder for opening a `MultiLayerCache` with non-default sled and eviction settings.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, process};
+
+use sled::Mode;
+
+use crate::listener::ListenerConfig;
+use crate::{
+    BudgetEnforcement, CreedmoorError, Delivery, EvictionPolicy, Lru, MultiLayerCache, RemovalListener, Result, SizeModel, TinyLfu,
+    UsageDrift,
+};
+
+/// Settings for opening a `MultiLayerCache`.
+///
+/// Nothing is checked until `build`, which validates the whole configuration before sled is
+/// opened. The defaults match `MultiLayerCache::new`.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use creedmoor::{CacheConfig, Mode};
+///
+/// let cache = CacheConfig::new(64 * 1024 * 1024)
+///     .path("/var/cache/thumbnails")
+///     .memory_budget(16 * 1024 * 1024)
+///     .compression(Some(3))
+///     .mode(Mode::HighThroughput)
+///     .max_object_size(1024 * 1024)
+///     .default_ttl(Duration::from_secs(3600))
+///     .build()?;
+/// # Ok::<(), creedmoor::CreedmoorError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub(crate) disk_budget: usize,
+    pub(crate) memory_budget: usize,
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) temporary: bool,
+    pub(crate) compression: Option<i32>,
+    pub(crate) flush_interval: Option<Duration>,
+    pub(crate) mode: Mode,
+    pub(crate) max_object_size: Option<usize>,
+    pub(crate) default_ttl: Option<Duration>,
+    pub(crate) size_model: SizeModel,
+    pub(crate) usage_drift: UsageDrift,
+    pub(crate) budget_enforcement: BudgetEnforcement,
+    pub(crate) persist_stats: bool,
+    pub(crate) eviction_policy: Arc<dyn EvictionPolicy>,
+    pub(crate) admission_filter: Option<TinyLfu>,
+    pub(crate) removal_listener: Option<ListenerConfig>,
+}
+
+impl CacheConfig {
+    /// Default size of sled's page cache, 1 GiB, the same as sled's own default.
+    pub const DEFAULT_MEMORY_BUDGET: usize = 1024 * 1024 * 1024;
+    /// Default zstd level, kept at what `MultiLayerCache::new` has always used.
+    pub const DEFAULT_COMPRESSION: i32 = 9;
+    /// Default interval between sled's background flushes.
+    pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// Start a configuration for a cache holding at most `disk_budget` bytes on disk.
+    pub fn new(disk_budget: usize) -> Self {
+        Self {
+            disk_budget,
+            memory_budget: Self::DEFAULT_MEMORY_BUDGET,
+            path: None,
+            temporary: false,
+            compression: Some(Self::DEFAULT_COMPRESSION),
+            flush_interval: Some(Self::DEFAULT_FLUSH_INTERVAL),
+            mode: Mode::LowSpace,
+            max_object_size: None,
+            default_ttl: None,
+            size_model: SizeModel::default(),
+            usage_drift: UsageDrift::default(),
+            budget_enforcement: BudgetEnforcement::default(),
+            persist_stats: false,
+            eviction_policy: Arc::new(Lru),
+            admission_filter: None,
+            removal_listener: None,
+        }
+    }
+
+    /// Max bytes held in sled's page cache.
+    pub fn memory_budget(mut self, memory_budget: usize) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// Directory of the sled database. Required unless the cache is temporary.
+    pub fn path(mut self, path: impl AsRef<Path>) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Delete the database shortly after the last clone of the cache is dropped, including
+    /// while unwinding from a panic; sled does it from its own threads. Without a path sled picks a fresh directory, under `/dev/shm`
+    /// where available. With a path, that whole directory is deleted.
+    pub fn temporary(mut self, temporary: bool) -> Self {
+        self.temporary = temporary;
+        self
+    }
+
+    /// Put a temporary database in a fresh directory under `std::env::temp_dir()`.
+    ///
+    /// Unlike `temporary` on its own this stays on disk rather than in shared memory, for
+    /// scratch caches that may not fit in RAM.
+    pub fn temp_dir(mut self) -> Self {
+        static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let name = format!(
+            "creedmoor-{}-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed),
+            nanos
+        );
+        self.path = Some(env::temp_dir().join(name));
+        self.temporary = true;
+        self
+    }
+
+    /// zstd level from 1 to 22, or `None` to store values uncompressed.
+    ///
+    /// Higher levels are much slower to write. sled refuses to reopen a database with
+    /// compression turned on or off differently from when it was created, but the level can
+    /// change freely.
+    pub fn compression(mut self, level: Option<i32>) -> Self {
+        self.compression = level;
+        self
+    }
+
+    /// How often sled flushes in the background, or `None` to only flush when asked.
+    pub fn flush_interval(mut self, flush_interval: Option<Duration>) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Whether sled favours disk space or write throughput.
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Reject objects charged more than `max_object_size` bytes with
+    /// `CreedmoorError::CacheObjectSizeTooLarge`. Defaults to `disk_budget`.
+    pub fn max_object_size(mut self, max_object_size: usize) -> Self {
+        self.max_object_size = Some(max_object_size);
+        self
+    }
+
+    /// Expire objects written with `put` once `ttl` has passed.
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// How objects are charged against the disk budget, see `SizeModel`.
+    pub fn size_model(mut self, size_model: SizeModel) -> Self {
+        self.size_model = size_model;
+        self
+    }
+
+    /// How the disk usage counter handles drift, see `UsageDrift`.
+    pub fn usage_drift(mut self, usage_drift: UsageDrift) -> Self {
+        self.usage_drift = usage_drift;
+        self
+    }
+
+    /// Which measure of disk usage is held to the budget, see `BudgetEnforcement`.
+    pub fn budget_enforcement(mut self, budget_enforcement: BudgetEnforcement) -> Self {
+        self.budget_enforcement = budget_enforcement;
+        self
+    }
+
+    /// Which objects to evict first when the cache is over budget. Defaults to `Lru`.
+    ///
+    /// Objects already in the cache keep the rank the previous policy gave them until they are
+    /// next written or hit.
+    pub fn eviction_policy(mut self, eviction_policy: impl EvictionPolicy + 'static) -> Self {
+        self.eviction_policy = Arc::new(eviction_policy);
+        self
+    }
+
+    /// Turn away new objects that would evict more popular ones, see `TinyLfu`. Off by default.
+    ///
+    /// The sketch is kept in sled next to the cache, so estimates survive a restart.
+    pub fn admission_filter(mut self, admission_filter: TinyLfu) -> Self {
+        self.admission_filter = Some(admission_filter);
+        self
+    }
+
+    /// Tell `listener` about every object that leaves the cache, once the removal has
+    /// committed. See `RemovalCause` for the ways objects leave.
+    pub fn removal_listener(mut self, listener: impl RemovalListener + 'static, delivery: Delivery) -> Self {
+        self.removal_listener = Some(ListenerConfig { listener: Arc::new(listener), delivery });
+        self
+    }
+
+    /// Keep the `CacheStats` counters in sled so they carry on from where they were after a
+    /// restart. They are saved by `MultiLayerCache::save_stats` and when the last clone of the
+    /// cache is dropped.
+    pub fn persist_stats(mut self, persist_stats: bool) -> Self {
+        self.persist_stats = persist_stats;
+        self
+    }
+
+    /// Check the configuration without opening anything.
+    pub fn validate(&self) -> Result<()> {
+        if self.disk_budget == 0 {
+            return Err(invalid("disk_budget must be at least 1 byte"));
+        }
+        if self.path.is_none() && !self.temporary {
+            return Err(invalid("a path is required unless the cache is temporary"));
+        }
+        if let Some(level) = self.compression {
+            if !(1..=22).contains(&level) {
+                return Err(invalid(format!("compression level must be between 1 and 22, got {}", level)));
+            }
+        }
+        if self.flush_interval.is_some_and(|interval| interval.as_millis() == 0) {
+            return Err(invalid("flush_interval must be at least 1ms, use None to disable background flushes"));
+        }
+        if let Some(max_object_size) = self.max_object_size {
+            if max_object_size == 0 || max_object_size > self.disk_budget {
+                return Err(invalid(format!(
+                    "max_object_size must be between 1 and disk_budget ({}), got {}",
+                    self.disk_budget, max_object_size
+                )));
+            }
+        }
+        if self.default_ttl == Some(Duration::ZERO) {
+            return Err(invalid("default_ttl must be longer than zero"));
+        }
+        if let Some(admission_filter) = self.admission_filter {
+            admission_filter.validate()?;
+        }
+        self.eviction_policy.validate()
+    }
+
+    /// Validate the configuration, then open the sled database and the cache in it.
+    pub fn build(self) -> Result<MultiLayerCache> {
+        self.validate()?;
+        let db = self.sled_config().open()?;
+        MultiLayerCache::open(db, &self, None)
+    }
+
+    pub(crate) fn sled_config(&self) -> sled::Config {
+        let mut config = sled::Config::new()
+            .cache_capacity(self.memory_budget as u64)
+            .mode(self.mode)
+            .temporary(self.temporary)
+            .flush_every_ms(self.flush_interval.map(|interval| interval.as_millis() as u64))
+            .use_compression(self.compression.is_some());
+        if let Some(level) = self.compression {
+            config = config.compression_factor(level);
+        }
+        if let Some(path) = &self.path {
+            config = config.path(path);
+        }
+        config
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> CreedmoorError {
+    CreedmoorError::InvalidConfig(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{reopen, TestDir};
+    use std::time::Instant;
+    use crate::WTinyLfu;
+
+    #[test]
+    fn test_validate() {
+        let sled_path = TestDir::new();
+        let valid = CacheConfig::new(1024).path(&sled_path);
+        assert!(valid.validate().is_ok());
+        let wtinylfu = |sketch| WTinyLfu { sketch, ..WTinyLfu::default() };
+        let invalid_configs = [
+            CacheConfig::new(0).path(&sled_path),
+            CacheConfig::new(1024),
+            valid.clone().compression(Some(0)),
+            valid.clone().compression(Some(23)),
+            valid.clone().flush_interval(Some(Duration::ZERO)),
+            valid.clone().max_object_size(0),
+            valid.clone().max_object_size(1025),
+            valid.clone().default_ttl(Duration::ZERO),
+            valid.clone().admission_filter(TinyLfu { expected_objects: 0, ..TinyLfu::default() }),
+            valid.clone().admission_filter(TinyLfu { persist_every: 0, ..TinyLfu::default() }),
+            valid.clone().eviction_policy(wtinylfu(TinyLfu { expected_objects: 0, ..TinyLfu::default() })),
+            valid.clone().eviction_policy(wtinylfu(TinyLfu { persist_every: 0, ..TinyLfu::default() })),
+        ];
+        for config in invalid_configs {
+            assert!(
+                matches!(config.clone().build(), Err(CreedmoorError::InvalidConfig(_))),
+                "{:?} should be rejected",
+                config
+            );
+        }
+        // Nothing was opened for the rejected configurations
+        assert!(!sled_path.as_ref().exists());
+    }
+
+    #[test]
+    fn test_build() {
+        let sled_path = TestDir::new();
+        let cache = CacheConfig::new(1024)
+            .path(&sled_path)
+            .memory_budget(1024)
+            .compression(None)
+            .flush_interval(None)
+            .mode(Mode::HighThroughput)
+            .max_object_size(10)
+            .size_model(SizeModel::KeyAndValue)
+            .default_ttl(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        cache.put(b"key", b"value").unwrap();
+        assert_eq!(cache.logical_disk_usage().unwrap(), 8);
+        assert!(cache.entry_info(b"key").unwrap().unwrap().expires_at.is_some());
+        assert!(matches!(
+            cache.put(b"key", b"too long"),
+            Err(CreedmoorError::CacheObjectSizeTooLarge(11))
+        ));
+        drop(cache);
+        // sled won't reopen an uncompressed database with compression turned on
+        let reopened = reopen(|| match CacheConfig::new(1024).path(&sled_path).build() {
+            Err(CreedmoorError::SledError(sled::Error::Io(error))) => Err(CreedmoorError::SledError(sled::Error::Io(error))),
+            reopened => Ok(reopened),
+        });
+        assert!(
+            matches!(&reopened, Err(CreedmoorError::SledError(sled::Error::Unsupported(reason))) if reason.contains("compression")),
+            "{:?}",
+            reopened.err()
+        );
+    }
+
+    #[test]
+    fn test_temporary() {
+        let cache = CacheConfig::new(1024).temporary(true).build().unwrap();
+        cache.put(b"key", b"value").unwrap();
+        assert_eq!(cache.get(b"key").unwrap().unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_temp_dir() {
+        let config = CacheConfig::new(1024).temp_dir();
+        let path = config.path.clone().unwrap();
+        assert!(path.starts_with(env::temp_dir()));
+        assert_ne!(CacheConfig::new(1024).temp_dir().path, Some(path.clone()));
+        let cache = config.build().unwrap();
+        let clone = cache.clone();
+        cache.put(b"key", b"value").unwrap();
+        assert!(path.exists());
+        drop(cache);
+        assert_eq!(clone.get(b"key").unwrap().unwrap(), b"value");
+        drop(clone);
+        // sled deletes the directory from its own threads, shortly after the last handle goes
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while path.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!path.exists());
+    }
+}
@@ -1,12 +1,48 @@
-use get_size::GetSize;
 use std::path::Path;
-use sled::{Db, Transactional, Tree};
-use sled::transaction::TransactionalTree;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sled::{Db, IVec, Transactional, Tree};
+use sled::transaction::{abort, ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionalTree};
 
 use thiserror::Error;
 
+use admission::Admission;
+use listener::{Notifier, Removal, RemovalCause};
+use policy::{PolicyMaintenance, PolicyMemory, PolicyTx};
+use registry::GlobalBudget;
+use stats::StatsCounters;
+
+pub mod admission;
+#[cfg(feature = "async")]
+pub mod async_cache;
+pub mod codec;
+pub mod config;
+pub mod listener;
+pub mod policy;
+pub mod registry;
+pub mod stats;
+pub mod typed;
+
+pub use admission::TinyLfu;
+#[cfg(feature = "async")]
+pub use async_cache::AsyncMultiLayerCache;
+pub use codec::Codec;
+pub use config::CacheConfig;
+pub use listener::{Delivery, RemovalListener};
+pub use policy::{Clock, EvictionPolicy, Fifo, Lfu, Lru, Slru, WTinyLfu};
+pub use registry::CacheRegistry;
+pub use stats::CacheStats;
+pub use sled::Mode;
+pub use typed::TypedCache;
+
 pub type Result<T> = core::result::Result<T, CreedmoorError>;
 
+/// Result of a closure or helper running inside a sled transaction. Our own errors abort it.
+pub type TxResult<T> = ConflictableTransactionResult<T, CreedmoorError>;
+
 #[derive(Error, Debug)]
 pub enum CreedmoorError {
     #[error("Got error on sled operation, was: {0}")]
@@ -19,6 +55,36 @@ pub enum CreedmoorError {
     SledConflictableTransactionError(#[from] sled::transaction::ConflictableTransactionError),
     #[error("Object size too large: {0}")]
     CacheObjectSizeTooLarge(usize),
+    #[error("Corrupt cache metadata: {0}")]
+    CorruptMetadata(String),
+    #[error("Object marked for eviction is missing, key was: {0:?}")]
+    MissingEvictionVictim(Vec<u8>),
+    #[error("Disk usage underflow: cannot subtract {sub} from {current}")]
+    UsageUnderflow { current: u64, sub: u64 },
+    #[error("Failed to encode or decode a cached value: {0}")]
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Object not admitted: estimated to be used {candidate} times, less than the {victim} of an object it would evict")]
+    NotAdmitted { candidate: u8, victim: u8 },
+    #[error("Invalid cache configuration: {0}")]
+    InvalidConfig(String),
+    #[cfg(feature = "async")]
+    #[error("Blocking cache operation failed: {0}")]
+    BlockingTask(#[from] tokio::task::JoinError),
+}
+
+impl From<TransactionError<CreedmoorError>> for CreedmoorError {
+    fn from(error: TransactionError<CreedmoorError>) -> Self {
+        match error {
+            TransactionError::Abort(error) => error,
+            TransactionError::Storage(error) => CreedmoorError::SledError(error),
+        }
+    }
+}
+
+impl From<CreedmoorError> for ConflictableTransactionError<CreedmoorError> {
+    fn from(error: CreedmoorError) -> Self {
+        ConflictableTransactionError::Abort(error)
+    }
 }
 
 pub enum Op {
@@ -26,11 +92,396 @@ pub enum Op {
     Sub,
 }
 
+/// The disk usage counter.
+///
+/// Always stored as an 8 byte big-endian `u64` so the on-disk format doesn't depend on the
+/// platform's `usize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct DiskUsage(u64);
+
+impl DiskUsage {
+    pub fn new(bytes: u64) -> Self {
+        Self(bytes)
+    }
+
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, bytes: u64) -> Option<Self> {
+        self.0.checked_add(bytes).map(Self)
+    }
+
+    pub fn checked_sub(self, bytes: u64) -> Option<Self> {
+        self.0.checked_sub(bytes).map(Self)
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self> {
+        decode_u64(bytes, "disk usage counter").map(Self)
+    }
+
+    pub(crate) fn encode(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    /// Convert to `usize`, failing if the counter can't be represented on this platform.
+    pub fn to_usize(self) -> Result<usize> {
+        usize::try_from(self.0).map_err(|_| {
+            CreedmoorError::CorruptMetadata(format!("disk usage {} does not fit in usize", self.0))
+        })
+    }
+}
+
+/// How many bytes an object is charged against the disk budget.
+///
+/// The charge is recorded with the object when it is written, so eviction always gives back
+/// exactly what was charged, even if the model is changed for an existing database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeModel {
+    /// Only the value's bytes.
+    #[default]
+    ValueOnly,
+    /// The key's and the value's bytes.
+    KeyAndValue,
+    /// Key and value plus the `OBJECT_LRU` and `OBJECT_INDEX` records kept for the object, its
+    /// `OBJECT_EXPIRY` record if it has a time-to-live, and an estimate of sled's overhead for
+    /// each of those tree entries.
+    ///
+    /// Ranks are counted as the 8-byte ids of `Lru`, `Fifo` and `Clock`; the longer ranks of
+    /// `Slru` and `WTinyLfu` (9 bytes) and `Lfu` (16 bytes) are undercounted by the difference.
+    KeyValueAndMetadata,
+}
+
+impl SizeModel {
+    /// Rough per-entry overhead of a sled tree entry, covering its node header and length prefixes.
+    pub const SLED_ENTRY_OVERHEAD: usize = 32;
+    /// Length of an `OBJECT_LRU` key under `Lru`, `Fifo` and `Clock`
+    const LRU_KEY_LEN: usize = 8;
+    /// Length of the expiry time prefixing `OBJECT_EXPIRY` keys
+    const EXPIRY_TIME_LEN: usize = 8;
+    /// Length of the size suffix of `OBJECT_LRU` and `OBJECT_INDEX` values
+    const SIZE_LEN: usize = 8;
+
+    /// The number of bytes to charge for storing `value` under `key`.
+    pub fn charge(self, key: &[u8], value: &[u8]) -> usize {
+        self.charge_lengths(key.len(), value.len())
+    }
+
+    /// The number of bytes to charge for storing `value` under `key` with a time-to-live.
+    pub fn charge_with_ttl(self, key: &[u8], value: &[u8]) -> usize {
+        let expiry_record = match self {
+            SizeModel::ValueOnly | SizeModel::KeyAndValue => 0,
+            SizeModel::KeyValueAndMetadata => Self::EXPIRY_TIME_LEN + key.len() + Self::SLED_ENTRY_OVERHEAD,
+        };
+        self.charge(key, value) + expiry_record
+    }
+
+    /// The number of bytes to charge for a key and value of the given lengths, without a
+    /// time-to-live.
+    pub fn charge_lengths(self, key_len: usize, value_len: usize) -> usize {
+        match self {
+            SizeModel::ValueOnly => value_len,
+            SizeModel::KeyAndValue => key_len + value_len,
+            SizeModel::KeyValueAndMetadata => {
+                let lru_record = Self::LRU_KEY_LEN + key_len + Self::SIZE_LEN;
+                let index_record = key_len + IndexEntry::FIXED_LEN + Self::LRU_KEY_LEN;
+                key_len + value_len + lru_record + index_record + 3 * Self::SLED_ENTRY_OVERHEAD
+            }
+        }
+    }
+}
+
+/// What to do when a subtraction would take the disk usage counter below zero.
+///
+/// That only happens if the counter has drifted from the sizes recorded for the cached objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UsageDrift {
+    /// Abort the operation with `CreedmoorError::UsageUnderflow`.
+    #[default]
+    Error,
+    /// Clamp the counter at zero, then recompute it from `OBJECT_DATA` once the transaction commits.
+    Repair,
+}
+
+/// Which measure of disk usage is held to `disk_budget`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BudgetEnforcement {
+    /// Only the logical counter of charged bytes, checked on every put.
+    #[default]
+    Logical,
+    /// Only sled's on-disk size, as reported by `Db::size_on_disk`, checked at most once per
+    /// `check_interval` after a put.
+    Physical { check_interval: Duration },
+    /// The logical counter on every put and the on-disk size once per `check_interval`.
+    Both { check_interval: Duration },
+}
+
+impl BudgetEnforcement {
+    fn logical(self) -> bool {
+        matches!(self, BudgetEnforcement::Logical | BudgetEnforcement::Both { .. })
+    }
+
+    fn check_interval(self) -> Option<Duration> {
+        match self {
+            BudgetEnforcement::Logical => None,
+            BudgetEnforcement::Physical { check_interval } | BudgetEnforcement::Both { check_interval } => Some(check_interval),
+        }
+    }
+}
+
+/// Metadata about a cached object, as returned by `MultiLayerCache::entry_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// Bytes charged against the disk budget, see `SizeModel`
+    pub size: usize,
+    /// When the object was last written
+    pub inserted_at: SystemTime,
+    /// When the object was last written or read with `get`; reads only count under policies
+    /// that track hits, see `EvictionPolicy::tracks_hits`
+    pub last_accessed_at: SystemTime,
+    /// Id of the object's current rank, see `EvictionPolicy`; under `Lru` larger is more
+    /// recently used
+    pub lru_position: u64,
+    /// Number of `get` hits since the object was last written, always 0 under policies that
+    /// don't track hits
+    pub hits: u64,
+    /// When the object's time-to-live runs out, if it has one
+    pub expires_at: Option<SystemTime>,
+}
+
+/// An `OBJECT_INDEX` record: where the object sits in `OBJECT_LRU` and its bookkeeping.
+///
+/// Encoded as big-endian `u64`s for the size, timestamps, hit count and expiry time (zero for
+/// none), followed by the `OBJECT_LRU` key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub(crate) lru_key: Vec<u8>,
+    pub(crate) size: usize,
+    /// Milliseconds since the UNIX epoch
+    pub(crate) inserted_at: u64,
+    /// Milliseconds since the UNIX epoch
+    pub(crate) accessed_at: u64,
+    pub(crate) hits: u64,
+    /// Milliseconds since the UNIX epoch
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl IndexEntry {
+    const FIXED_LEN: usize = 40;
+
+    fn new(lru_key: &[u8], size: usize, expires_at: Option<u64>) -> Self {
+        let now = now_millis();
+        Self {
+            lru_key: lru_key.to_vec(),
+            size,
+            inserted_at: now,
+            accessed_at: now,
+            hits: 0,
+            expires_at,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::FIXED_LEN + self.lru_key.len());
+        bytes.extend_from_slice(&(self.size as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.inserted_at.to_be_bytes());
+        bytes.extend_from_slice(&self.accessed_at.to_be_bytes());
+        bytes.extend_from_slice(&self.hits.to_be_bytes());
+        bytes.extend_from_slice(&self.expires_at.unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(&self.lru_key);
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < Self::FIXED_LEN {
+            return Err(CreedmoorError::CorruptMetadata(format!("expected an index entry of at least {} bytes, got {} bytes", Self::FIXED_LEN, bytes.len())));
+        }
+        let field = |n: usize| decode_u64(&bytes[n * 8..(n + 1) * 8], "index entry field");
+        Ok(Self {
+            size: DiskUsage::new(field(0)?).to_usize()?,
+            inserted_at: field(1)?,
+            accessed_at: field(2)?,
+            hits: field(3)?,
+            expires_at: Some(field(4)?).filter(|&expires_at| expires_at != 0),
+            lru_key: bytes[Self::FIXED_LEN..].to_vec(),
+        })
+    }
+
+    fn info(&self) -> EntryInfo {
+        // Ranks end in an id from `PolicyTx::next_id`
+        let lru_position = self.lru_key.len().checked_sub(8).map_or(0, |start| {
+            let mut position = [0u8; 8];
+            position.copy_from_slice(&self.lru_key[start..]);
+            u64::from_be_bytes(position)
+        });
+        EntryInfo {
+            size: self.size,
+            inserted_at: UNIX_EPOCH + Duration::from_millis(self.inserted_at),
+            last_accessed_at: UNIX_EPOCH + Duration::from_millis(self.accessed_at),
+            lru_position,
+            hits: self.hits,
+            expires_at: self.expires_at.map(|expires_at| UNIX_EPOCH + Duration::from_millis(expires_at)),
+        }
+    }
+}
+
+/// Decode a big-endian `u64`, reporting `what` was malformed if it isn't exactly 8 bytes.
+pub(crate) fn decode_u64(bytes: &[u8], what: &str) -> Result<u64> {
+    let bytes: [u8; 8] = bytes.try_into().map_err(|_| {
+        CreedmoorError::CorruptMetadata(format!("expected an 8 byte {}, got {} bytes", what, bytes.len()))
+    })?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// An entry chosen for eviction, decoded from its `OBJECT_LRU` record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionVictim {
+    /// Key of the victim's `OBJECT_LRU` entry
+    pub(crate) lru_key: IVec,
+    /// Key of the object in `OBJECT_DATA`
+    pub(crate) key: Vec<u8>,
+    /// Size charged for the object when it was inserted
+    pub(crate) size: usize,
+}
+
+impl EvictionVictim {
+    pub fn from_lru_entry(lru_key: IVec, key_and_size: &[u8]) -> Result<Self> {
+        let (key, size) = MultiLayerCache::split_size(key_and_size)?;
+        Ok(Self {
+            lru_key,
+            key: key.to_vec(),
+            size,
+        })
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// The sled trees that make up a cache.
+#[derive(Clone)]
+pub(crate) struct CacheTrees {
+    pub(crate) data: Tree,
+    pub(crate) lru: Tree,
+    pub(crate) index: Tree,
+    pub(crate) expiry: Tree,
+    pub(crate) disk_usage: Tree,
+    /// All of the above followed by the eviction policy's state trees, in the order
+    /// `transaction` hands them out
+    all: Vec<Tree>,
+}
+
+/// Transactional views of `CacheTrees`, handed to the closure run by `CacheTrees::transaction`.
+pub(crate) struct CacheTx<'a> {
+    pub(crate) data: &'a TransactionalTree,
+    pub(crate) lru: &'a TransactionalTree,
+    pub(crate) index: &'a TransactionalTree,
+    pub(crate) expiry: &'a TransactionalTree,
+    pub(crate) disk_usage: &'a TransactionalTree,
+    pub(crate) policy: &'a [TransactionalTree],
+}
+
+impl CacheTrees {
+    fn open(db: &Db, namespace: Option<&str>, policy: &dyn EvictionPolicy) -> Result<Self> {
+        let open = |tree: &[u8]| db.open_tree(MultiLayerCache::tree_name(namespace, tree));
+        let data = open(MultiLayerCache::OBJECT_DATA)?;
+        let lru = open(MultiLayerCache::OBJECT_LRU)?;
+        let index = open(MultiLayerCache::OBJECT_INDEX)?;
+        let expiry = open(MultiLayerCache::OBJECT_EXPIRY)?;
+        let disk_usage = open(MultiLayerCache::DISK_USAGE_TREE)?;
+        let mut all = vec![data.clone(), lru.clone(), index.clone(), expiry.clone(), disk_usage.clone()];
+        for tree in policy.state_trees() {
+            all.push(open(tree)?);
+        }
+        Ok(Self { data, lru, index, expiry, disk_usage, all })
+    }
+
+    /// The eviction policy's `index`th state tree.
+    pub(crate) fn policy_state(&self, index: usize) -> &Tree {
+        &self.all[5 + index]
+    }
+
+    /// Run `f` atomically across all of the cache's trees.
+    fn transaction<A>(&self, f: impl Fn(&CacheTx) -> TxResult<A>) -> Result<A> {
+        let result = self.all.as_slice().transaction(|trees| {
+            let [data, lru, index, expiry, disk_usage, policy @ ..] = trees.as_slice() else {
+                unreachable!("a cache always has its five trees");
+            };
+            f(&CacheTx { data, lru, index, expiry, disk_usage, policy })
+        })?;
+        Ok(result)
+    }
+}
+
+/// Outcome of one attempt at the transaction in `MultiLayerCache::put_inner`.
+enum PutAttempt {
+    /// Other writers consumed some eviction candidates; gather at least this many bytes and retry
+    Shortfall(usize),
+    /// The admission filter turned the object away
+    Rejected { candidate: u8, victim: u8 },
+    Written { replaced: bool, evictions: usize, evicted_bytes: usize, removals: Vec<Removal> },
+}
+
+/// Handle to the background thread started by `MultiLayerCache::spawn_expiry_purger`.
+///
+/// The thread stops when the handle is dropped.
+pub struct ExpiryPurger {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ExpiryPurger {
+    fn drop(&mut self) {
+        // Hanging up wakes the thread, which then exits
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 /// Multi-layer LRU cache: memory + sled (disk).
+///
+/// Cloning is cheap and the clones share the same sled `Db`, so a cache can be handed to many
+/// threads without an external lock.
+#[derive(Clone)]
 pub struct MultiLayerCache {
     pub(crate) disk_budget: usize,
+    /// Largest charge accepted for a single object
+    pub(crate) max_object_size: usize,
     /// Sled database for on-disk storage
     pub(crate) db: Db,
+    pub(crate) trees: CacheTrees,
+    pub(crate) size_model: SizeModel,
+    pub(crate) usage_drift: UsageDrift,
+    /// Set when the counter was clamped under `UsageDrift::Repair` and needs recomputing
+    pub(crate) usage_drifted: Arc<AtomicBool>,
+    pub(crate) budget_enforcement: BudgetEnforcement,
+    /// When the on-disk size was last checked against the budget
+    pub(crate) last_physical_check: Arc<Mutex<Instant>>,
+    /// Time-to-live applied by `put`
+    pub(crate) default_ttl: Option<Duration>,
+    /// Cap shared with the other namespaces of a `CacheRegistry`
+    pub(crate) global_budget: Option<Arc<GlobalBudget>>,
+    pub(crate) stats: Arc<StatsCounters>,
+    pub(crate) policy: Arc<dyn EvictionPolicy>,
+    pub(crate) policy_memory: Arc<PolicyMemory>,
+    /// TinyLFU filter consulted by puts that would evict
+    pub(crate) admission: Option<Arc<Admission>>,
+    pub(crate) notifier: Option<Arc<Notifier>>,
 }
 
 impl MultiLayerCache {
@@ -38,147 +489,661 @@ impl MultiLayerCache {
     pub(crate) const DISK_USAGE_KEY: &'static [u8; 7] = b"current";
     pub(crate) const OBJECT_LRU: &'static [u8; 10] = b"object_lru";
     pub(crate) const OBJECT_DATA: &'static [u8; 11] = b"object_data";
+    /// Reverse index from object key to its `IndexEntry`: current `OBJECT_LRU` key, recorded size
+    /// and access bookkeeping.
+    pub(crate) const OBJECT_INDEX: &'static [u8; 12] = b"object_index";
+    /// Entries with a time-to-live, keyed by big-endian expiry time followed by the object key.
+    pub(crate) const OBJECT_EXPIRY: &'static [u8; 13] = b"object_expiry";
+    /// Cumulative `CacheStats` counters, only written with `CacheConfig::persist_stats`.
+    pub(crate) const CACHE_STATS: &'static [u8; 11] = b"cache_stats";
+
+    /// Create a multi-layer cache on a temporary sled database that is deleted when the last
+    /// clone of the cache is dropped. See `CacheConfig::temporary` and `CacheConfig::temp_dir`.
+    pub fn temporary(memory_budget: usize, disk_budget: usize) -> Result<Self> {
+        CacheConfig::new(disk_budget)
+            .memory_budget(memory_budget)
+            .temporary(true)
+            .build()
+    }
 
     /// Create a new multi-layer cache backed by sled on disk.
     ///
     /// * `memory_budget`: max bytes in memory
     /// * `disk_budget`: max bytes on disk
     /// * `sled_path`: path to the sled database
+    ///
+    /// Use `CacheConfig` to tune compression, flushing and eviction.
     pub fn new(memory_budget: usize, disk_budget: usize, sled_path: impl AsRef<Path>) -> Result<Self> {
-        let path = sled_path.as_ref().to_path_buf();
-        // let db = sled::open(&path)?;
-        let db =
-            sled::Config::new()
-                .cache_capacity(memory_budget as u64)
-                .use_compression(true)
-                .compression_factor(9)
-                .path(path).open()?;
+        CacheConfig::new(disk_budget)
+            .memory_budget(memory_budget)
+            .path(sled_path)
+            .build()
+    }
+
+    /// Open the cache trees in `db` with the settings from a validated `config`, prefixing
+    /// their names with `namespace` if there is one.
+    pub(crate) fn open(db: Db, config: &CacheConfig, namespace: Option<&str>) -> Result<Self> {
+        let stats = if config.persist_stats {
+            StatsCounters::load(db.open_tree(Self::tree_name(namespace, Self::CACHE_STATS))?)?
+        } else {
+            StatsCounters::default()
+        };
+        let admission = match config.admission_filter {
+            Some(filter) => Some(Arc::new(Admission::load(db.open_tree(Self::tree_name(namespace, Admission::SKETCH_TREE))?, filter)?)),
+            None => None,
+        };
         Ok(Self {
-            disk_budget,
+            disk_budget: config.disk_budget,
+            max_object_size: config.max_object_size.unwrap_or(config.disk_budget),
+            trees: CacheTrees::open(&db, namespace, config.eviction_policy.as_ref())?,
             db,
+            size_model: config.size_model,
+            usage_drift: config.usage_drift,
+            usage_drifted: Arc::new(AtomicBool::new(false)),
+            budget_enforcement: config.budget_enforcement,
+            last_physical_check: Arc::new(Mutex::new(Instant::now())),
+            default_ttl: config.default_ttl,
+            global_budget: None,
+            stats: Arc::new(stats),
+            policy: config.eviction_policy.clone(),
+            policy_memory: Arc::new(Mutex::new(None)),
+            admission,
+            notifier: config.removal_listener.as_ref().map(|listener| Arc::new(Notifier::new(listener))),
         })
     }
 
-    fn ivec_to_u64(ivec: sled::IVec) -> u64 {
-        let ivec = ivec.as_ref();
-        let ivec = u64::from_be_bytes([ivec[0], ivec[1], ivec[2], ivec[3], ivec[4], ivec[5], ivec[6], ivec[7]]);
-        ivec
+    /// Name of `tree` in `namespace`, or of the unprefixed tree for a standalone cache.
+    pub(crate) fn tree_name(namespace: Option<&str>, tree: &[u8]) -> Vec<u8> {
+        match namespace {
+            Some(namespace) => [namespace.as_bytes(), &[CacheRegistry::SEPARATOR as u8], tree].concat(),
+            None => tree.to_vec(),
+        }
     }
 
-    fn get_disk_usage_(&self) -> Option<usize> {
-        let disk_usage = self.db.open_tree(Self::DISK_USAGE_TREE).unwrap();
-        let current = disk_usage.get(Self::DISK_USAGE_KEY).unwrap();
-        match current {
-            Some(current) => {
-                let current = current.as_ref();
-                let current = u64::from_be_bytes([current[0], current[1], current[2], current[3], current[4], current[5], current[6], current[7]]);
-                Some(current as usize)
-            }
-            None => None,
+    /// Append the size to `prefix` as a big-endian `u64`, the layout of `OBJECT_LRU` values.
+    fn with_size(prefix: &[u8], size: usize) -> Vec<u8> {
+        let mut bytes = prefix.to_vec();
+        bytes.extend_from_slice(&(size as u64).to_be_bytes());
+        bytes
+    }
+
+    /// Split a value produced by `with_size` back into its prefix and size.
+    fn split_size(bytes: &[u8]) -> Result<(&[u8], usize)> {
+        let split = bytes.len().checked_sub(8).ok_or_else(|| {
+            CreedmoorError::CorruptMetadata(format!("expected a record of at least 8 bytes, got {} bytes", bytes.len()))
+        })?;
+        let (prefix, size_bytes) = bytes.split_at(split);
+        let size = DiskUsage::new(decode_u64(size_bytes, "object size")?).to_usize()?;
+        Ok((prefix, size))
+    }
+
+    /// Key of the `OBJECT_EXPIRY` record for `key` expiring at `expires_at`.
+    fn expiry_key(expires_at: u64, key: &[u8]) -> Vec<u8> {
+        let mut bytes = expires_at.to_be_bytes().to_vec();
+        bytes.extend_from_slice(key);
+        bytes
+    }
+
+    fn get_disk_usage_(&self) -> Result<Option<usize>> {
+        match self.trees.disk_usage.get(Self::DISK_USAGE_KEY)? {
+            Some(current) => Ok(Some(DiskUsage::decode(&current)?.to_usize()?)),
+            None => Ok(None),
         }
     }
 
-    fn populate_disk_usage(&self) -> usize {
-        let current = match self.get_disk_usage_() {
-            Some(current) => current,
+    fn populate_disk_usage(&self) -> Result<usize> {
+        match self.get_disk_usage_()? {
+            Some(current) => Ok(current),
             None => {
-                let disk_usage = self.db.open_tree(Self::DISK_USAGE_TREE).unwrap();
-                disk_usage.insert(Self::DISK_USAGE_KEY, &0u64.to_be_bytes()).unwrap();
-                0
+                // Only initialize if no writer got there first, a put may have committed since we looked
+                let _ = self.trees.disk_usage
+                    .compare_and_swap(Self::DISK_USAGE_KEY, None as Option<&[u8]>, Some(&DiskUsage::default().encode()))?;
+                Ok(self.get_disk_usage_()?.unwrap_or(0))
             }
-        };
-        current
+        }
     }
 
-    fn get_disk_usage(&self) -> usize {
+    fn get_disk_usage(&self) -> Result<usize> {
         self.populate_disk_usage()
     }
 
-    fn update_disk_usage(&self, disk_usage_tree: &TransactionalTree, operand: Op, value: usize) -> Result<usize> {
-        let result: Result<usize> = {
-            let current = disk_usage_tree.get(Self::DISK_USAGE_KEY)?;
-            let current = match current {
-                Some(current) => {
-                    let current = Self::ivec_to_u64(current);
-                    current as usize
+    /// Bytes charged to the disk budget by the cached objects, see `SizeModel`.
+    pub fn logical_disk_usage(&self) -> Result<usize> {
+        self.get_disk_usage()
+    }
+
+    /// Size of sled's storage files for the whole database.
+    pub fn physical_disk_usage(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    fn read_disk_usage(&self, disk_usage_tree: &TransactionalTree) -> TxResult<usize> {
+        let current = disk_usage_tree.get(Self::DISK_USAGE_KEY)?;
+        Ok(match current {
+            Some(current) => DiskUsage::decode(&current)?.to_usize()?,
+            None => 0,
+        })
+    }
+
+    fn update_disk_usage(&self, disk_usage_tree: &TransactionalTree, operand: Op, value: usize) -> TxResult<usize> {
+        let current = match disk_usage_tree.get(Self::DISK_USAGE_KEY)? {
+            Some(current) => DiskUsage::decode(&current)?,
+            None => DiskUsage::default(),
+        };
+        let value = value as u64;
+        let new_value = match operand {
+            Op::Add => match current.checked_add(value) {
+                Some(new_value) => new_value,
+                None => return abort(CreedmoorError::CorruptMetadata(format!("disk usage {} overflows when adding {}", current.bytes(), value))),
+            },
+            Op::Sub => match (current.checked_sub(value), self.usage_drift) {
+                (Some(new_value), _) => new_value,
+                (None, UsageDrift::Error) => return abort(CreedmoorError::UsageUnderflow { current: current.bytes(), sub: value }),
+                (None, UsageDrift::Repair) => {
+                    self.usage_drifted.store(true, Ordering::SeqCst);
+                    DiskUsage::default()
                 }
-                None => 0,
-            };
-            let new_value = match operand {
-                Op::Add => current + value,
-                Op::Sub => current - value,
-            };
-            disk_usage_tree.insert(Self::DISK_USAGE_KEY, &new_value.to_be_bytes())?;
-            Ok(new_value)
+            },
         };
-        Ok(result?)
+        disk_usage_tree.insert(Self::DISK_USAGE_KEY, &new_value.encode())?;
+        Ok(new_value.to_usize()?)
+    }
+
+    /// Recompute the disk usage counter from the sizes recorded for every object in `OBJECT_DATA`,
+    /// store it and return it.
+    ///
+    /// The objects are walked outside of a transaction, so the result is only exact while no
+    /// other thread is writing to the cache.
+    pub fn recompute_disk_usage(&self) -> Result<usize> {
+        let mut total = DiskUsage::default();
+        for key in self.trees.data.iter().keys() {
+            let key = key?;
+            if let Some(entry) = self.trees.index.get(&key)? {
+                let size = IndexEntry::decode(&entry)?.size;
+                total = total.checked_add(size as u64).ok_or_else(|| {
+                    CreedmoorError::CorruptMetadata("recorded object sizes overflow the disk usage counter".to_string())
+                })?;
+            }
+        }
+        self.trees.disk_usage.insert(Self::DISK_USAGE_KEY, &total.encode())?;
+        total.to_usize()
+    }
+
+    /// Recompute the counter if a transaction clamped it under `UsageDrift::Repair`.
+    fn repair_disk_usage_if_drifted(&self) -> Result<()> {
+        if self.usage_drifted.swap(false, Ordering::SeqCst) {
+            self.recompute_disk_usage()?;
+        }
+        Ok(())
     }
 
-    fn fetch_add_disk_usage(&self, disk_usage_tree: &TransactionalTree, add: usize) -> Result<usize> {
+    fn fetch_add_disk_usage(&self, disk_usage_tree: &TransactionalTree, add: usize) -> TxResult<usize> {
         self.update_disk_usage(disk_usage_tree, Op::Add, add)
     }
 
-    fn fetch_sub_disk_usage(&self, disk_usage_tree: &TransactionalTree, sub: usize) -> Result<usize> {
+    fn fetch_sub_disk_usage(&self, disk_usage_tree: &TransactionalTree, sub: usize) -> TxResult<usize> {
         self.update_disk_usage(disk_usage_tree, Op::Sub, sub)
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        let size = value.get_size();
-        if size > self.disk_budget {
+    /// Cache `value` under `key`, expiring it after the default time-to-live if one is configured.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_inner(key, value, self.default_ttl)
+    }
+
+    /// Cache `value` under `key`, expiring it once `ttl` has passed.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.put_inner(key, value, Some(ttl))
+    }
+
+    fn put_inner(&self, key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<()> {
+        let size = match ttl {
+            Some(_) => self.size_model.charge_with_ttl(key, value),
+            None => self.size_model.charge(key, value),
+        };
+        if size > self.max_object_size {
+            StatsCounters::add(&self.stats.rejected_oversize, 1);
             return Err(CreedmoorError::CacheObjectSizeTooLarge(size));
         }
-        let instant = std::time::Instant::now();
-        let instant_bytes = instant.elapsed().as_nanos().to_be_bytes();
+        if let Some(admission) = &self.admission {
+            admission.record(key)?;
+        }
+        self.policy.accessed(&self.maintenance(), key)?;
         // convert key_and_size to bytes
-        let mut key_and_size = key.to_vec();
-        key_and_size.extend_from_slice(&size.to_be_bytes());
-        let disk_usage = self.get_disk_usage();
-        let target_storage = size + disk_usage;
-        let excess = target_storage - disk_usage;
-        let object_data = self.db.open_tree(Self::OBJECT_DATA)?;
-        let object_lru = self.db.open_tree(Self::OBJECT_LRU)?;
-        let disk_usage = self.db.open_tree(Self::DISK_USAGE_TREE)?;
-        let disk_budget = self.disk_budget;
-        let (total_to_evict, keys_to_evict) = self.gather_keys_for_eviction(&object_lru, excess)?;
-        (&object_lru, &object_data, &disk_usage).transaction(|(lru, data, disk_usage)| {
-            // TODO: Fix-up the error types to purge the expect later
-            println!("target_storage: {}, disk_budget: {}", target_storage, disk_budget);
-            if target_storage > disk_budget {
-                self.evict_bytes(disk_usage, data, &keys_to_evict, total_to_evict).expect("Failed to evict bytes");
-            }
-            self.fetch_add_disk_usage(disk_usage, size).expect("Failed to add disk usage");
-            data.insert(key, value)?;
-            // Insert key and size so we don't have to re-compute object size on eviction
-            lru.insert(&instant_bytes, key_and_size.clone())?;
-            Ok(())
-        })?;
+        let key_and_size = Self::with_size(key, size);
+        let expires_at = ttl.map(|ttl| now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX)));
+        // Without logical enforcement an unreachable budget keeps puts from evicting
+        let disk_budget = if self.budget_enforcement.logical() { self.disk_budget } else { usize::MAX };
+        let mut excess = (self.get_disk_usage()? + size).saturating_sub(disk_budget);
+        loop {
+            // Candidates are only read here; they are re-checked and removed inside the
+            // transaction so a failed or retried put can't lose `OBJECT_LRU` entries.
+            let (candidates, exhausted) = self.policy.gather_victims(&self.maintenance(), Some(key), excess)?;
+            let attempt = self.trees.transaction(|tx| {
+                let current = self.read_disk_usage(tx.disk_usage)?;
+                let previous = match tx.index.get(key)? {
+                    Some(previous) => Some(IndexEntry::decode(&previous)?),
+                    None => None,
+                };
+                let previous_size = previous.as_ref().map_or(0, |previous| previous.size);
+                // An expired entry is overwritten like any other, but the put counts as an insert
+                let replaced = previous.as_ref().is_some_and(|previous| !previous.is_expired(now_millis()));
+                let excess = (current + size).saturating_sub(previous_size).saturating_sub(disk_budget);
+                let victims = Self::select_victims(tx, &candidates, excess)?;
+                let selected: usize = victims.iter().map(|victim| victim.size).sum();
+                if selected < excess && !exhausted {
+                    // Other writers consumed some candidates; gather again before writing anything
+                    return Ok(PutAttempt::Shortfall(excess));
+                }
+                if let (false, Some(admission)) = (replaced, &self.admission) {
+                    if let Some((candidate, victim)) = admission.rejects(key, &victims) {
+                        return Ok(PutAttempt::Rejected { candidate, victim });
+                    }
+                }
+                let mut removals = Vec::new();
+                // An overwrite replaces the previous LRU and expiry entries and gives back its bytes
+                if let Some(previous) = previous {
+                    tx.lru.remove(previous.lru_key.as_slice())?;
+                    self.policy.removed(&self.policy_tx(tx), key, &previous.lru_key, previous.size, false)?;
+                    if let Some(previous_expires_at) = previous.expires_at {
+                        tx.expiry.remove(Self::expiry_key(previous_expires_at, key))?;
+                    }
+                    self.fetch_sub_disk_usage(tx.disk_usage, previous.size)?;
+                }
+                let evicted_bytes = self.evict_bytes(tx, &victims, &mut removals)?;
+                self.fetch_add_disk_usage(tx.disk_usage, size)?;
+                if let Some(previous_value) = tx.data.insert(key, value)? {
+                    let cause = if replaced { RemovalCause::Replaced } else { RemovalCause::Expired };
+                    self.record_removal(&mut removals, key, previous_value, previous_size, cause);
+                }
+                // Insert key and size so we don't have to re-compute object size on eviction
+                let lru_key = self.policy.rank_inserted(&self.policy_tx(tx), key, size)?;
+                tx.lru.insert(lru_key.as_slice(), key_and_size.clone())?;
+                if let Some(expires_at) = expires_at {
+                    tx.expiry.insert(Self::expiry_key(expires_at, key), &[])?;
+                }
+                tx.index.insert(key, IndexEntry::new(&lru_key, size, expires_at).encode())?;
+                Ok(PutAttempt::Written { replaced, evictions: victims.len(), evicted_bytes, removals })
+            })?;
+            match attempt {
+                PutAttempt::Shortfall(needed) => excess = needed,
+                PutAttempt::Rejected { candidate, victim } => {
+                    StatsCounters::add(&self.stats.rejected_admission, 1);
+                    return Err(CreedmoorError::NotAdmitted { candidate, victim });
+                }
+                PutAttempt::Written { replaced, evictions, evicted_bytes, removals } => {
+                    StatsCounters::add(if replaced { &self.stats.overwrites } else { &self.stats.inserts }, 1);
+                    self.stats.record_evictions(evictions, evicted_bytes);
+                    self.notify(removals);
+                    break;
+                }
+            }
+        }
+        self.policy.maintain(&self.maintenance())?;
+        self.repair_disk_usage_if_drifted()?;
+        self.maybe_enforce_physical_budget()?;
+        if let Some(global_budget) = &self.global_budget {
+            global_budget.enforce()?;
+        }
         Ok(())
     }
 
-    fn gather_keys_for_eviction(&self, lru_tree: &Tree, excess: usize) -> Result<(usize, Vec<Vec<u8>>)> {
-        let mut total_evicted = 0;
-        let mut keys_to_evict = Vec::new();
-        while total_evicted < excess {
-            if let Some((key, value)) = lru_tree.pop_min()? {
-                let value_vec = value.to_vec();
-                // grab the last eight bytes and construct the size: usize, use split
-                let (_key_bytes, size_bytes) = value_vec.split_at(value_vec.len() - 8);
-                let size = usize::from_be_bytes([size_bytes[0], size_bytes[1], size_bytes[2], size_bytes[3], size_bytes[4], size_bytes[5], size_bytes[6], size_bytes[7]]);
-                total_evicted += size;
-                keys_to_evict.push(key.to_vec());
-            } else {
+    /// Run `enforce_physical_budget` if the configured check interval has passed.
+    fn maybe_enforce_physical_budget(&self) -> Result<()> {
+        let Some(check_interval) = self.budget_enforcement.check_interval() else {
+            return Ok(());
+        };
+        {
+            // Another thread holding the lock is already checking
+            let Ok(mut last_check) = self.last_physical_check.try_lock() else {
+                return Ok(());
+            };
+            if last_check.elapsed() < check_interval {
+                return Ok(());
+            }
+            *last_check = Instant::now();
+        }
+        self.enforce_physical_budget()?;
+        Ok(())
+    }
+
+    /// Compare sled's on-disk size against `disk_budget` and evict if it is over.
+    ///
+    /// Freed pages don't shrink sled's files right away, so rather than evicting until the
+    /// on-disk size drops, this evicts the same share of the logical usage as the on-disk size
+    /// is over budget by. Repeated checks converge as sled reclaims space. Returns the number of
+    /// bytes evicted.
+    pub fn enforce_physical_budget(&self) -> Result<usize> {
+        let physical = self.physical_disk_usage()?;
+        if physical <= self.disk_budget as u64 {
+            return Ok(0);
+        }
+        let logical = self.get_disk_usage()? as u128;
+        let target = logical * self.disk_budget as u128 / physical as u128;
+        self.evict((logical - target) as usize)
+    }
+
+    /// Evict at least `bytes` from the least recently used end of the cache in one transaction.
+    /// Returns the number of bytes evicted.
+    fn evict(&self, bytes: usize) -> Result<usize> {
+        if bytes == 0 {
+            return Ok(0);
+        }
+        loop {
+            let (candidates, exhausted) = self.policy.gather_victims(&self.maintenance(), None, bytes)?;
+            let evicted = self.trees.transaction(|tx| {
+                let victims = Self::select_victims(tx, &candidates, bytes)?;
+                let selected: usize = victims.iter().map(|victim| victim.size).sum();
+                if selected < bytes && !exhausted {
+                    return Ok(None);
+                }
+                let mut removals = Vec::new();
+                let evicted = self.evict_bytes(tx, &victims, &mut removals)?;
+                Ok(Some((victims.len(), evicted, removals)))
+            })?;
+            if let Some((evictions, evicted, removals)) = evicted {
+                self.stats.record_evictions(evictions, evicted);
+                self.notify(removals);
+                self.policy.maintain(&self.maintenance())?;
+                self.repair_disk_usage_if_drifted()?;
+                return Ok(evicted);
+            }
+        }
+    }
+
+    /// Look up `key`, letting the eviction policy re-rank it on a hit.
+    ///
+    /// An expired entry is a miss and is removed on the spot.
+    pub fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        if let Some(admission) = &self.admission {
+            admission.record(key)?;
+        }
+        self.policy.accessed(&self.maintenance(), key)?;
+        let tracks_hits = self.policy.tracks_hits();
+        // Without hit tracking a hit writes nothing, so it only needs plain reads; misses and
+        // expired entries still go through the transaction
+        let mut untracked_hit = None;
+        if !tracks_hits {
+            if let Some(entry) = self.live_entry(key)? {
+                untracked_hit = self.trees.data.get(key)?.map(|value| (value, entry.lru_key));
+            }
+        }
+        let (value, untracked_rank, removals) = match untracked_hit {
+            Some((value, rank)) => (Some(value), Some(rank), Vec::new()),
+            None => self.trees.transaction(|tx| {
+                let Some(entry) = tx.index.get(key)? else {
+                    return Ok((None, None, Vec::new()));
+                };
+                let mut entry = IndexEntry::decode(&entry)?;
+                let now = now_millis();
+                if entry.is_expired(now) {
+                    let mut removals = Vec::new();
+                    if let Some((value, size, cause)) = self.remove_entry(tx, key, RemovalCause::Expired)? {
+                        self.record_removal(&mut removals, key, value, size, cause);
+                    }
+                    return Ok((None, None, removals));
+                }
+                let Some(value) = tx.data.get(key)? else {
+                    return Ok((None, None, Vec::new()));
+                };
+                if !tracks_hits {
+                    // Written since the plain read missed it
+                    return Ok((Some(value), Some(entry.lru_key), Vec::new()));
+                }
+                entry.accessed_at = now;
+                entry.hits += 1;
+                if let Some(new_lru_key) = self.policy.rank_hit(&self.policy_tx(tx), key, &entry.lru_key, entry.size, entry.hits)? {
+                    let key_and_size = tx.lru.remove(entry.lru_key.as_slice())?.unwrap_or_else(|| Self::with_size(key, entry.size).into());
+                    tx.lru.insert(new_lru_key.as_slice(), key_and_size)?;
+                    entry.lru_key = new_lru_key;
+                }
+                tx.index.insert(key, entry.encode())?;
+                Ok((Some(value), None, Vec::new()))
+            })?,
+        };
+        self.notify(removals);
+        if let Some(rank) = untracked_rank {
+            self.policy.hit(&self.maintenance(), key, &rank)?;
+        }
+        if value.is_some() {
+            StatsCounters::add(&self.stats.hits, 1);
+            self.policy.maintain(&self.maintenance())?;
+        } else {
+            StatsCounters::add(&self.stats.misses, 1);
+        }
+        self.repair_disk_usage_if_drifted()?;
+        Ok(value)
+    }
+
+    /// Snapshot of the hit, miss and eviction counters and the current disk usage.
+    pub fn stats(&self) -> Result<CacheStats> {
+        Ok(self.stats.snapshot(self.get_disk_usage()?, self.disk_budget))
+    }
+
+    /// Write the counters to sled now rather than when the last clone of the cache is dropped.
+    /// Does nothing unless `CacheConfig::persist_stats` is set.
+    pub fn save_stats(&self) -> Result<()> {
+        self.stats.save()
+    }
+
+    /// The index entry for `key`, unless it is missing or expired.
+    fn live_entry(&self, key: &[u8]) -> Result<Option<IndexEntry>> {
+        match self.trees.index.get(key)? {
+            Some(entry) => {
+                let entry = IndexEntry::decode(&entry)?;
+                Ok((!entry.is_expired(now_millis())).then_some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Look up `key` without changing its recency or access bookkeeping.
+    pub fn peek(&self, key: &[u8]) -> Result<Option<IVec>> {
+        if self.live_entry(key)?.is_none() {
+            return Ok(None);
+        }
+        Ok(self.trees.data.get(key)?)
+    }
+
+    /// Whether `key` is cached, without changing its recency or access bookkeeping.
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.live_entry(key)?.is_some())
+    }
+
+    /// Size, timestamps, recency position and hit count for `key`, if it is cached.
+    pub fn entry_info(&self, key: &[u8]) -> Result<Option<EntryInfo>> {
+        Ok(self.live_entry(key)?.map(|entry| entry.info()))
+    }
+
+    /// Remove `key`, returning its value if it was cached.
+    pub fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+        Ok(self.take(key)?.map(|(value, _size)| value))
+    }
+
+    /// Remove `key`, returning its value and the size it was charged if it was cached.
+    ///
+    /// An expired entry is removed too, but not returned.
+    pub fn take(&self, key: &[u8]) -> Result<Option<(IVec, usize)>> {
+        let removed = self.trees.transaction(|tx| self.remove_entry(tx, key, RemovalCause::Explicit))?;
+        let mut removals = Vec::new();
+        if let Some((value, size, cause)) = &removed {
+            self.record_removal(&mut removals, key, value.clone(), *size, *cause);
+        }
+        self.notify(removals);
+        self.repair_disk_usage_if_drifted()?;
+        Ok(removed.filter(|(_, _, cause)| *cause == RemovalCause::Explicit).map(|(value, size, _)| (value, size)))
+    }
+
+    /// Remove every cached object in one transaction. Returns the number of objects removed,
+    /// not counting expired ones.
+    ///
+    /// Objects written by other threads while the keys are being collected are left alone.
+    pub fn clear(&self) -> Result<usize> {
+        let keys = self.trees.data.iter().keys().collect::<sled::Result<Vec<IVec>>>()?;
+        let (removed, removals) = self.trees.transaction(|tx| {
+            let mut removed = 0;
+            let mut removals = Vec::new();
+            for key in &keys {
+                if let Some((value, size, cause)) = self.remove_entry(tx, key, RemovalCause::Cleared)? {
+                    removed += (cause == RemovalCause::Cleared) as usize;
+                    self.record_removal(&mut removals, key, value, size, cause);
+                }
+            }
+            Ok((removed, removals))
+        })?;
+        self.notify(removals);
+        self.repair_disk_usage_if_drifted()?;
+        Ok(removed)
+    }
+
+    /// Remove every entry whose time-to-live has run out, giving its bytes back to the disk
+    /// budget. Returns the number of entries removed.
+    pub fn purge_expired(&self) -> Result<usize> {
+        let now = now_millis();
+        // `OBJECT_EXPIRY` is ordered by expiry time, so the expired records are a prefix of it
+        let bound = (now + 1).to_be_bytes();
+        let mut keys = Vec::new();
+        for expiry_key in self.trees.expiry.range(..&bound[..]).keys() {
+            let expiry_key = expiry_key?;
+            let key = expiry_key.get(8..).ok_or_else(|| {
+                CreedmoorError::CorruptMetadata(format!("expected an expiry record of at least 8 bytes, got {} bytes", expiry_key.len()))
+            })?;
+            keys.push(key.to_vec());
+        }
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let (purged, removals) = self.trees.transaction(|tx| {
+            let mut purged = 0;
+            let mut removals = Vec::new();
+            for key in &keys {
+                // The entry may have been rewritten with a new expiry since the scan
+                let Some(entry) = tx.index.get(key)? else {
+                    continue;
+                };
+                if !IndexEntry::decode(&entry)?.is_expired(now) {
+                    continue;
+                }
+                if let Some((value, size, cause)) = self.remove_entry(tx, key, RemovalCause::Expired)? {
+                    purged += 1;
+                    self.record_removal(&mut removals, key, value, size, cause);
+                }
+            }
+            Ok((purged, removals))
+        })?;
+        self.notify(removals);
+        self.repair_disk_usage_if_drifted()?;
+        Ok(purged)
+    }
+
+    /// Run `purge_expired` every `interval` on a background thread until the returned handle is
+    /// dropped. Errors are discarded; call `purge_expired` directly to observe them.
+    pub fn spawn_expiry_purger(&self, interval: Duration) -> ExpiryPurger {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let cache = self.clone();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let _ = cache.purge_expired();
+            }
+        });
+        ExpiryPurger {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Remove `key`'s object, `OBJECT_LRU`, `OBJECT_EXPIRY` and index entries, subtracting its
+    /// recorded size. Returns the removed value and size if the key was cached, with `cause`,
+    /// or `RemovalCause::Expired` if the entry had already expired.
+    fn remove_entry(&self, tx: &CacheTx, key: &[u8], cause: RemovalCause) -> TxResult<Option<(IVec, usize, RemovalCause)>> {
+        let Some(value) = tx.data.remove(key)? else {
+            return Ok(None);
+        };
+        let Some(entry) = tx.index.remove(key)? else {
+            return abort(CreedmoorError::CorruptMetadata(format!("cached object has no index entry, key was: {:?}", key)));
+        };
+        let entry = IndexEntry::decode(&entry)?;
+        tx.lru.remove(entry.lru_key.as_slice())?;
+        self.policy.removed(&self.policy_tx(tx), key, &entry.lru_key, entry.size, false)?;
+        if let Some(expires_at) = entry.expires_at {
+            tx.expiry.remove(Self::expiry_key(expires_at, key))?;
+        }
+        self.fetch_sub_disk_usage(tx.disk_usage, entry.size)?;
+        let cause = if entry.is_expired(now_millis()) { RemovalCause::Expired } else { cause };
+        Ok(Some((value, entry.size, cause)))
+    }
+
+    /// Add a notification of `key` leaving the cache to `removals`, if there is a removal
+    /// listener. Transactions collect them to deliver with `notify` once they commit.
+    fn record_removal(&self, removals: &mut Vec<Removal>, key: &[u8], value: IVec, size: usize, cause: RemovalCause) {
+        if let Some(notifier) = &self.notifier {
+            removals.push(notifier.removal(key, value, size, cause));
+        }
+    }
+
+    fn notify(&self, removals: Vec<Removal>) {
+        if let Some(notifier) = self.notifier.as_ref().filter(|_| !removals.is_empty()) {
+            notifier.deliver(removals);
+        }
+    }
+
+    /// The eviction policy's view of the cache outside of transactions.
+    fn maintenance(&self) -> PolicyMaintenance<'_> {
+        PolicyMaintenance { cache: self }
+    }
+
+    /// The eviction policy's view of the transaction `tx`.
+    pub(crate) fn policy_tx<'a>(&'a self, tx: &'a CacheTx) -> PolicyTx<'a> {
+        PolicyTx {
+            lru: tx.lru,
+            index: tx.index,
+            state: tx.policy,
+            disk_budget: self.disk_budget,
+        }
+    }
+
+    /// Inside a transaction, keep the candidates that are still current until `excess` is covered.
+    ///
+    /// A candidate is current if its `OBJECT_LRU` entry still exists and the index still points at it.
+    fn select_victims(tx: &CacheTx, candidates: &[EvictionVictim], excess: usize) -> TxResult<Vec<EvictionVictim>> {
+        let mut selected = 0;
+        let mut victims = Vec::new();
+        for candidate in candidates {
+            if selected >= excess {
                 break;
             }
+            if tx.lru.get(&candidate.lru_key)?.is_none() {
+                continue;
+            }
+            match tx.index.get(&candidate.key)? {
+                Some(entry) if IndexEntry::decode(&entry)?.lru_key == candidate.lru_key.as_ref() => {}
+                _ => continue,
+            }
+            selected += candidate.size;
+            victims.push(candidate.clone());
         }
-        Ok((total_evicted, keys_to_evict))
+        Ok(victims)
     }
 
-    fn evict_bytes(&self, disk_usage_tree: &TransactionalTree, data_tree: &TransactionalTree, keys_to_evict: &[Vec<u8>], total_to_evict: usize) -> Result<()> {
-        for key in keys_to_evict {
-            let _size = data_tree.remove(key.as_slice())?.expect(&format!("Failed to remove key marked for eviction, key was: {:?}", key));
+    /// Remove each victim's object and bookkeeping records, subtracting the sizes recorded for
+    /// them and adding them to `removals`. Returns the number of bytes evicted.
+    fn evict_bytes(&self, tx: &CacheTx, victims: &[EvictionVictim], removals: &mut Vec<Removal>) -> TxResult<usize> {
+        let mut total_evicted = 0;
+        for victim in victims {
+            tx.lru.remove(&victim.lru_key)?;
+            self.policy.removed(&self.policy_tx(tx), &victim.key, &victim.lru_key, victim.size, true)?;
+            if let Some(entry) = tx.index.remove(victim.key.as_slice())? {
+                if let Some(expires_at) = IndexEntry::decode(&entry)?.expires_at {
+                    tx.expiry.remove(Self::expiry_key(expires_at, &victim.key))?;
+                }
+            }
+            let Some(value) = tx.data.remove(victim.key.as_slice())? else {
+                return abort(CreedmoorError::MissingEvictionVictim(victim.key.clone()));
+            };
+            self.record_removal(removals, &victim.key, value, victim.size, RemovalCause::Evicted);
+            total_evicted += victim.size;
         }
-        self.fetch_sub_disk_usage(disk_usage_tree, total_to_evict)?;
-        Ok(())
+        self.fetch_sub_disk_usage(tx.disk_usage, total_evicted)?;
+        Ok(total_evicted)
     }
 }
 
@@ -187,46 +1152,512 @@ mod tests {
     use super::*;
     use std::fs;
     use std::path::PathBuf;
+    use std::sync::atomic::AtomicU64;
+    use std::{env, process, thread};
+
+    /// A database directory for tests that reopen their cache, so they can't be temporary.
+    /// Unique to the test and deleted when dropped.
+    pub(crate) struct TestDir(PathBuf);
+
+    impl TestDir {
+        pub(crate) fn new() -> Self {
+            static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+            let name = format!("creedmoor-test-{}-{}", process::id(), NEXT_DIR.fetch_add(1, Ordering::Relaxed));
+            Self(env::temp_dir().join(name))
+        }
+    }
+
+    impl AsRef<Path> for TestDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Open a database that was just closed, waiting out sled's IO threads, which can hold its
+    /// lock file for a moment after the last handle is dropped.
+    pub(crate) fn reopen<T>(open: impl Fn() -> Result<T>) -> T {
+        for _ in 0..100 {
+            match open() {
+                Err(CreedmoorError::SledError(sled::Error::Io(error))) if error.to_string().contains("could not acquire lock") => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                opened => return opened.unwrap(),
+            }
+        }
+        open().unwrap()
+    }
 
     #[test]
     fn test_new() {
         let memory_budget = 1024;
         let disk_budget = 1024;
-        let sled_path = PathBuf::from("/tmp/sled-test-new");
-        let _cache = MultiLayerCache::new(memory_budget, disk_budget, sled_path.clone()).unwrap();
-        fs::remove_dir_all(sled_path).unwrap();
+        let _cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
     }
 
     #[test]
     fn test_put() {
         let memory_budget = 1024;
         let disk_budget = 1024;
-        let sled_path = PathBuf::from("/tmp/sled-test-put");
-        let mut cache = MultiLayerCache::new(memory_budget, disk_budget, sled_path.clone()).unwrap();
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
         let key = b"key";
         let value = b"value";
         cache.put(key, value).unwrap();
-        fs::remove_dir_all(sled_path).unwrap();
+    }
+
+    #[test]
+    fn test_get() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        cache.put(b"key", b"value").unwrap();
+        assert_eq!(cache.get(b"key").unwrap().unwrap(), b"value");
+        assert_eq!(cache.get(b"missing").unwrap(), None);
+        // The hit moves the LRU entry rather than adding a second one
+        let lru_tree = cache.db.open_tree(MultiLayerCache::OBJECT_LRU).unwrap();
+        assert_eq!(lru_tree.len(), 1);
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        let value: &[u8] = b"second!";
+        cache.put(b"key", b"first").unwrap();
+        cache.put(b"key", value).unwrap();
+        assert_eq!(cache.get(b"key").unwrap().unwrap(), value);
+        let lru_tree = cache.db.open_tree(MultiLayerCache::OBJECT_LRU).unwrap();
+        assert_eq!(lru_tree.len(), 1);
+        assert_eq!(cache.get_disk_usage().unwrap(), value.len());
+    }
+
+    #[test]
+    fn test_lru_keys_increase() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let sled_path = TestDir::new();
+        let lru_key_of = |cache: &MultiLayerCache, key: &[u8]| {
+            let index_tree = cache.db.open_tree(MultiLayerCache::OBJECT_INDEX).unwrap();
+            let entry = index_tree.get(key).unwrap().unwrap();
+            IndexEntry::decode(&entry).unwrap().lru_key
+        };
+        let cache = MultiLayerCache::new(memory_budget, disk_budget, &sled_path).unwrap();
+        cache.put(b"a", b"1").unwrap();
+        cache.put(b"b", b"2").unwrap();
+        assert!(lru_key_of(&cache, b"a") < lru_key_of(&cache, b"b"));
+        cache.get(b"a").unwrap();
+        assert!(lru_key_of(&cache, b"a") > lru_key_of(&cache, b"b"));
+        let before_restart = lru_key_of(&cache, b"a");
+        drop(cache);
+
+        // Ordering keys keep increasing after the database is reopened
+        let cache = reopen(|| MultiLayerCache::new(memory_budget, disk_budget, &sled_path));
+        cache.put(b"c", b"3").unwrap();
+        assert!(lru_key_of(&cache, b"c") > before_restart);
     }
 
     #[test]
     fn test_size_limit() {
         let memory_budget = 1024;
         let disk_budget = 1024;
-        let sled_path = PathBuf::from("/tmp/sled-test-size-limit");
-        let mut cache = MultiLayerCache::new(memory_budget, disk_budget, sled_path.clone()).unwrap();
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
         for n in 0..1024u16 {
             let key = n.to_be_bytes();
             let value = n.to_be_bytes();
             cache.put(&key, &value).unwrap();
         }
+        // The most recently written entries that fit in the budget survive
+        let entry_size = 0u16.to_be_bytes().len();
+        let retained = (disk_budget / entry_size) as u16;
         let data_tree = cache.db.open_tree(MultiLayerCache::OBJECT_DATA).unwrap();
         let (min_key, _) = data_tree.pop_min().unwrap().unwrap();
         let (max_key, _)  = data_tree.pop_max().unwrap().unwrap();
         let min_key = u16::from_be_bytes([min_key[0], min_key[1]]);
         let max_key = u16::from_be_bytes([max_key[0], max_key[1]]);
-        assert_eq!(min_key, 0);
-        assert_eq!(max_key, 511);
-        fs::remove_dir_all(sled_path).unwrap();
+        assert_eq!(min_key, 1024 - retained);
+        assert_eq!(max_key, 1023);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_lru_eviction_order() {
+        let memory_budget = 1024;
+        let value: &[u8] = b"v";
+        let disk_budget = 3 * value.len();
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        cache.put(b"a", value).unwrap();
+        cache.put(b"b", value).unwrap();
+        cache.put(b"c", value).unwrap();
+        cache.get(b"a").unwrap();
+        cache.put(b"d", value).unwrap();
+        assert_eq!(cache.get(b"b").unwrap(), None);
+        for key in [b"a", b"c", b"d"] {
+            assert!(cache.get(key).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_repeated_eviction() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        let data_tree = cache.db.open_tree(MultiLayerCache::OBJECT_DATA).unwrap();
+        let lru_tree = cache.db.open_tree(MultiLayerCache::OBJECT_LRU).unwrap();
+        let index_tree = cache.db.open_tree(MultiLayerCache::OBJECT_INDEX).unwrap();
+        for round in 0..8u32 {
+            for n in 0..512u32 {
+                let key = (round * 512 + n).to_be_bytes();
+                // Vary sizes so eviction has to add up records rather than count them
+                let value = vec![0u8; (n % 7) as usize];
+                cache.put(&key, &value).unwrap();
+            }
+            let usage = cache.get_disk_usage().unwrap();
+            assert!(usage <= disk_budget);
+            let recorded: usize = lru_tree
+                .iter()
+                .values()
+                .map(|key_and_size| MultiLayerCache::split_size(&key_and_size.unwrap()).unwrap().1)
+                .sum();
+            assert_eq!(usage, recorded);
+            assert_eq!(data_tree.len(), lru_tree.len());
+            assert_eq!(data_tree.len(), index_tree.len());
+        }
+    }
+
+    #[test]
+    fn test_gather_does_not_remove() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        cache.put(b"a", b"1").unwrap();
+        cache.put(b"b", b"2").unwrap();
+        let lru_tree = cache.db.open_tree(MultiLayerCache::OBJECT_LRU).unwrap();
+        let (candidates, exhausted) = cache.policy.gather_victims(&cache.maintenance(), Some(b"b"), disk_budget).unwrap();
+        assert!(exhausted);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].key, b"a");
+        // Selection must not touch the tree outside of the put transaction
+        assert_eq!(lru_tree.len(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_put() {
+        fn assert_send_sync<T: Send + Sync + Clone>() {}
+        assert_send_sync::<MultiLayerCache>();
+
+        let memory_budget = 1024 * 1024;
+        let disk_budget = 4096;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        let handles: Vec<_> = (0..16u32)
+            .map(|thread_id| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for n in 0..256u32 {
+                        // Overlapping keys across threads exercise overwrites as well as evictions
+                        let key = ((thread_id * 256 + n) % 1024).to_be_bytes();
+                        let value = vec![thread_id as u8; (n % 13) as usize];
+                        cache.put(&key, &value).unwrap();
+                        cache.get(&key).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let data_tree = cache.db.open_tree(MultiLayerCache::OBJECT_DATA).unwrap();
+        let lru_tree = cache.db.open_tree(MultiLayerCache::OBJECT_LRU).unwrap();
+        let index_tree = cache.db.open_tree(MultiLayerCache::OBJECT_INDEX).unwrap();
+        let recorded: usize = lru_tree
+            .iter()
+            .values()
+            .map(|key_and_size| MultiLayerCache::split_size(&key_and_size.unwrap()).unwrap().1)
+            .sum();
+        let usage = cache.get_disk_usage().unwrap();
+        assert_eq!(usage, recorded);
+        assert!(usage <= disk_budget);
+        assert_eq!(data_tree.len(), lru_tree.len());
+        assert_eq!(data_tree.len(), index_tree.len());
+    }
+
+    #[test]
+    fn test_corrupt_disk_usage() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        let disk_usage_tree = cache.db.open_tree(MultiLayerCache::DISK_USAGE_TREE).unwrap();
+        disk_usage_tree.insert(MultiLayerCache::DISK_USAGE_KEY, &[0u8, 1, 2]).unwrap();
+        let result = cache.put(b"key", b"value");
+        assert!(matches!(result, Err(CreedmoorError::CorruptMetadata(_))));
+    }
+
+    #[test]
+    fn test_missing_eviction_victim() {
+        let memory_budget = 1024;
+        let value: &[u8] = b"v";
+        let disk_budget = 2 * value.len();
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        cache.put(b"a", value).unwrap();
+        cache.put(b"b", value).unwrap();
+        let data_tree = cache.db.open_tree(MultiLayerCache::OBJECT_DATA).unwrap();
+        data_tree.remove(b"a").unwrap();
+        let result = cache.put(b"c", value);
+        assert!(matches!(result, Err(CreedmoorError::MissingEvictionVictim(key)) if key == b"a"));
+        // The aborted put left every tree untouched
+        assert_eq!(data_tree.get(b"c").unwrap(), None);
+        let lru_tree = cache.db.open_tree(MultiLayerCache::OBJECT_LRU).unwrap();
+        assert_eq!(lru_tree.len(), 2);
+    }
+
+    #[test]
+    fn test_usage_underflow() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        cache.put(b"key", b"value").unwrap();
+        let disk_usage_tree = cache.db.open_tree(MultiLayerCache::DISK_USAGE_TREE).unwrap();
+        disk_usage_tree.insert(MultiLayerCache::DISK_USAGE_KEY, &0u64.to_be_bytes()).unwrap();
+        let result = cache.put(b"key", b"other");
+        assert!(matches!(result, Err(CreedmoorError::UsageUnderflow { current: 0, .. })));
+    }
+
+    #[test]
+    fn test_usage_drift_repair() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = CacheConfig::new(disk_budget)
+            .memory_budget(memory_budget)
+            .temporary(true)
+            .usage_drift(UsageDrift::Repair)
+            .build()
+            .unwrap();
+        let value: &[u8] = b"value";
+        cache.put(b"a", value).unwrap();
+        cache.put(b"b", value).unwrap();
+        let disk_usage_tree = cache.db.open_tree(MultiLayerCache::DISK_USAGE_TREE).unwrap();
+        disk_usage_tree.insert(MultiLayerCache::DISK_USAGE_KEY, &DiskUsage::new(0).encode()).unwrap();
+        // The overwrite would underflow, so the counter is clamped and then rebuilt
+        cache.put(b"a", value).unwrap();
+        assert_eq!(cache.get_disk_usage().unwrap(), 2 * value.len());
+    }
+
+    #[test]
+    fn test_recompute_disk_usage() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        let value: &[u8] = b"value";
+        for n in 0..10u8 {
+            cache.put(&[n], value).unwrap();
+        }
+        let disk_usage_tree = cache.db.open_tree(MultiLayerCache::DISK_USAGE_TREE).unwrap();
+        disk_usage_tree.insert(MultiLayerCache::DISK_USAGE_KEY, &DiskUsage::new(12345).encode()).unwrap();
+        assert_eq!(cache.recompute_disk_usage().unwrap(), 10 * value.len());
+        assert_eq!(cache.get_disk_usage().unwrap(), 10 * value.len());
+    }
+
+    #[test]
+    fn test_size_model() {
+        let key = b"key";
+        let value = b"value";
+        assert_eq!(SizeModel::ValueOnly.charge(key, value), 5);
+        assert_eq!(SizeModel::KeyAndValue.charge(key, value), 8);
+        assert_eq!(
+            SizeModel::KeyValueAndMetadata.charge(key, value),
+            8 + (8 + 3 + 8) + (3 + 40 + 8) + 3 * SizeModel::SLED_ENTRY_OVERHEAD
+        );
+        assert_eq!(SizeModel::KeyAndValue.charge_with_ttl(key, value), 8);
+        assert_eq!(
+            SizeModel::KeyValueAndMetadata.charge_with_ttl(key, value),
+            SizeModel::KeyValueAndMetadata.charge(key, value) + (8 + 3) + SizeModel::SLED_ENTRY_OVERHEAD
+        );
+
+        let memory_budget = 1024;
+        let size_model = SizeModel::KeyValueAndMetadata;
+        let disk_budget = 4 * size_model.charge(&[0u8; 2], &[0u8; 2]);
+        let cache = CacheConfig::new(disk_budget)
+            .memory_budget(memory_budget)
+            .temporary(true)
+            .size_model(size_model)
+            .build()
+            .unwrap();
+        for n in 0..16u16 {
+            cache.put(&n.to_be_bytes(), &n.to_be_bytes()).unwrap();
+        }
+        // Both puts and evictions use the same charge, so exactly four entries fit
+        assert_eq!(cache.get_disk_usage().unwrap(), disk_budget);
+        let data_tree = cache.db.open_tree(MultiLayerCache::OBJECT_DATA).unwrap();
+        assert_eq!(data_tree.len(), 4);
+    }
+
+    #[test]
+    fn test_physical_budget() {
+        let memory_budget = 1024;
+        let disk_budget = 64 * 1024;
+        let cache = CacheConfig::new(disk_budget)
+            .memory_budget(memory_budget)
+            .temporary(true)
+            .budget_enforcement(BudgetEnforcement::Physical { check_interval: Duration::from_secs(3600) })
+            .build()
+            .unwrap();
+        // Incompressible values, so sled's files end up well past the budget
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for n in 0..256u32 {
+            let value: Vec<u8> = (0..1024)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            cache.put(&n.to_be_bytes(), &value).unwrap();
+        }
+        cache.db.flush().unwrap();
+        // Logical usage isn't enforced in this mode, so nothing was evicted by the puts
+        assert_eq!(cache.logical_disk_usage().unwrap(), 256 * 1024);
+        assert!(cache.physical_disk_usage().unwrap() > disk_budget as u64);
+        let evicted = cache.enforce_physical_budget().unwrap();
+        assert!(evicted > 0);
+        assert_eq!(cache.logical_disk_usage().unwrap(), 256 * 1024 - evicted);
+        // The oldest entries went first
+        assert_eq!(cache.get(&0u32.to_be_bytes()).unwrap(), None);
+        assert!(cache.get(&255u32.to_be_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_remove_and_take() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        cache.put(b"a", b"first").unwrap();
+        cache.put(b"b", b"second").unwrap();
+        assert_eq!(cache.remove(b"a").unwrap().unwrap(), b"first");
+        assert_eq!(cache.remove(b"a").unwrap(), None);
+        assert_eq!(cache.get_disk_usage().unwrap(), 6);
+        let (value, size) = cache.take(b"b").unwrap().unwrap();
+        assert_eq!(value, b"second");
+        assert_eq!(size, 6);
+        assert_eq!(cache.get_disk_usage().unwrap(), 0);
+        let lru_tree = cache.db.open_tree(MultiLayerCache::OBJECT_LRU).unwrap();
+        let index_tree = cache.db.open_tree(MultiLayerCache::OBJECT_INDEX).unwrap();
+        assert!(lru_tree.is_empty());
+        assert!(index_tree.is_empty());
+    }
+
+    #[test]
+    fn test_clear() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        for n in 0..100u8 {
+            cache.put(&[n], &[n; 4]).unwrap();
+        }
+        assert_eq!(cache.clear().unwrap(), 100);
+        assert_eq!(cache.get_disk_usage().unwrap(), 0);
+        for tree in [MultiLayerCache::OBJECT_DATA.as_slice(), MultiLayerCache::OBJECT_LRU, MultiLayerCache::OBJECT_INDEX] {
+            assert!(cache.db.open_tree(tree).unwrap().is_empty());
+        }
+        // The cache is still usable afterwards
+        cache.put(b"key", b"value").unwrap();
+        assert_eq!(cache.get(b"key").unwrap().unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_peek_and_entry_info() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        let before = SystemTime::now() - Duration::from_millis(1);
+        cache.put(b"a", b"value").unwrap();
+        cache.put(b"b", b"value").unwrap();
+        let info = cache.entry_info(b"a").unwrap().unwrap();
+        assert_eq!(info.size, 5);
+        assert_eq!(info.hits, 0);
+        assert!(info.inserted_at >= before);
+        assert_eq!(info.inserted_at, info.last_accessed_at);
+
+        // Peeking and probing leave the bookkeeping alone
+        assert_eq!(cache.peek(b"a").unwrap().unwrap(), b"value");
+        assert!(cache.contains_key(b"a").unwrap());
+        assert!(!cache.contains_key(b"missing").unwrap());
+        assert_eq!(cache.peek(b"missing").unwrap(), None);
+        assert_eq!(cache.entry_info(b"a").unwrap().unwrap(), info);
+        assert_eq!(cache.entry_info(b"missing").unwrap(), None);
+
+        cache.get(b"a").unwrap();
+        cache.get(b"a").unwrap();
+        let touched = cache.entry_info(b"a").unwrap().unwrap();
+        assert_eq!(touched.hits, 2);
+        assert_eq!(touched.inserted_at, info.inserted_at);
+        assert!(touched.last_accessed_at >= info.last_accessed_at);
+        assert!(touched.lru_position > cache.entry_info(b"b").unwrap().unwrap().lru_position);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = CacheConfig::new(disk_budget)
+            .memory_budget(memory_budget)
+            .temporary(true)
+            .default_ttl(Duration::from_millis(300))
+            .build()
+            .unwrap();
+        cache.put(b"default", b"value").unwrap();
+        cache.put_with_ttl(b"short", b"value", Duration::ZERO).unwrap();
+        cache.put_with_ttl(b"long", b"value", Duration::from_secs(3600)).unwrap();
+        // Expired entries are invisible to every read path
+        assert_eq!(cache.peek(b"short").unwrap(), None);
+        assert!(!cache.contains_key(b"short").unwrap());
+        assert_eq!(cache.entry_info(b"short").unwrap(), None);
+        assert!(cache.entry_info(b"long").unwrap().unwrap().expires_at.is_some());
+        // A get removes the expired entry right away
+        assert_eq!(cache.get(b"short").unwrap(), None);
+        assert_eq!(cache.get_disk_usage().unwrap(), 10);
+        assert!(cache.get(b"default").unwrap().is_some());
+
+        thread::sleep(Duration::from_millis(350));
+        assert_eq!(cache.get_disk_usage().unwrap(), 10);
+        assert_eq!(cache.purge_expired().unwrap(), 1);
+        assert_eq!(cache.get_disk_usage().unwrap(), 5);
+        assert_eq!(cache.purge_expired().unwrap(), 0);
+        assert!(cache.get(b"long").unwrap().is_some());
+        let expiry_tree = cache.db.open_tree(MultiLayerCache::OBJECT_EXPIRY).unwrap();
+        assert_eq!(expiry_tree.len(), 1);
+    }
+
+    #[test]
+    fn test_huge_ttl() {
+        let cache = MultiLayerCache::temporary(1024, 1024).unwrap();
+        // More milliseconds than fit in a u64, so it saturates rather than wrapping
+        cache.put_with_ttl(b"key", b"value", Duration::from_secs(18_446_744_073_709_552)).unwrap();
+        cache.put_with_ttl(b"max", b"value", Duration::MAX).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        assert!(cache.get(b"key").unwrap().is_some());
+        assert!(cache.entry_info(b"max").unwrap().unwrap().expires_at.is_some());
+        assert_eq!(cache.purge_expired().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_expiry_purger() {
+        let memory_budget = 1024;
+        let disk_budget = 1024;
+        let cache = MultiLayerCache::temporary(memory_budget, disk_budget).unwrap();
+        for n in 0..10u8 {
+            cache.put_with_ttl(&[n], b"value", Duration::from_millis(10)).unwrap();
+        }
+        cache.put(b"kept", b"value").unwrap();
+        let purger = cache.spawn_expiry_purger(Duration::from_millis(20));
+        thread::sleep(Duration::from_millis(200));
+        drop(purger);
+        assert_eq!(cache.get_disk_usage().unwrap(), 5);
+        let data_tree = cache.db.open_tree(MultiLayerCache::OBJECT_DATA).unwrap();
+        assert_eq!(data_tree.len(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+//! Notifications for objects leaving a cache, see `CacheConfig::removal_listener`.
+
+use std::fmt;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use sled::IVec;
+
+/// Why an object left the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// Evicted to stay within a disk budget
+    Evicted,
+    /// Its time-to-live ran out, noticed by `purge_expired` or by any other operation on its key
+    Expired,
+    /// Overwritten by a put of the same key
+    Replaced,
+    /// Removed by `remove` or `take`
+    Explicit,
+    /// Removed by `clear`
+    Cleared,
+}
+
+/// An object that left the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Removal {
+    pub key: IVec,
+    /// The value it held, unless the listener turned values off with
+    /// `RemovalListener::wants_values`
+    pub value: Option<IVec>,
+    /// Bytes it was charged against the disk budget
+    pub size: usize,
+    pub cause: RemovalCause,
+}
+
+/// Told about every object that leaves a cache, once the removal has committed.
+///
+/// Implemented for closures taking a `Removal`.
+pub trait RemovalListener: Send + Sync {
+    fn on_removal(&self, removal: Removal);
+
+    /// Whether removals carry the removed value. Turn it off to keep large values out of the
+    /// delivery queue.
+    fn wants_values(&self) -> bool {
+        true
+    }
+}
+
+impl<F: Fn(Removal) + Send + Sync> RemovalListener for F {
+    fn on_removal(&self, removal: Removal) {
+        self(removal)
+    }
+}
+
+/// How removals reach a `RemovalListener`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Delivery {
+    /// Call the listener on the thread that removed the objects, before the cache operation
+    /// returns. A panicking listener panics that thread.
+    #[default]
+    Sync,
+    /// Queue removals, unbounded, for a background thread to deliver in order, so a slow
+    /// listener doesn't hold up the cache. The thread stops once the last clone of the cache
+    /// is dropped and the queue has drained.
+    Queued,
+}
+
+/// A listener and its delivery, as held by `CacheConfig`.
+#[derive(Clone)]
+pub(crate) struct ListenerConfig {
+    pub(crate) listener: Arc<dyn RemovalListener>,
+    pub(crate) delivery: Delivery,
+}
+
+impl fmt::Debug for ListenerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListenerConfig").field("delivery", &self.delivery).finish_non_exhaustive()
+    }
+}
+
+/// Delivers the removals of one cache, shared by its clones.
+pub(crate) enum Notifier {
+    Sync(Arc<dyn RemovalListener>),
+    Queued { queue: Sender<Removal>, wants_values: bool },
+}
+
+impl Notifier {
+    pub(crate) fn new(config: &ListenerConfig) -> Self {
+        match config.delivery {
+            Delivery::Sync => Notifier::Sync(config.listener.clone()),
+            Delivery::Queued => {
+                let (queue, removals) = mpsc::channel::<Removal>();
+                let listener = config.listener.clone();
+                thread::spawn(move || {
+                    for removal in removals {
+                        listener.on_removal(removal);
+                    }
+                });
+                Notifier::Queued { queue, wants_values: config.listener.wants_values() }
+            }
+        }
+    }
+
+    /// A removal to deliver, with the value only if the listener wants it.
+    pub(crate) fn removal(&self, key: &[u8], value: IVec, size: usize, cause: RemovalCause) -> Removal {
+        let wants_values = match self {
+            Notifier::Sync(listener) => listener.wants_values(),
+            Notifier::Queued { wants_values, .. } => *wants_values,
+        };
+        Removal { key: key.into(), value: wants_values.then_some(value), size, cause }
+    }
+
+    pub(crate) fn deliver(&self, removals: Vec<Removal>) {
+        match self {
+            Notifier::Sync(listener) => removals.into_iter().for_each(|removal| listener.on_removal(removal)),
+            Notifier::Queued { queue, .. } => {
+                for removal in removals {
+                    // Only fails if the listener panicked and took the delivery thread with it
+                    let _ = queue.send(removal);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CacheConfig;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn removal(key: &[u8], value: Option<&[u8]>, size: usize, cause: RemovalCause) -> Removal {
+        Removal { key: key.into(), value: value.map(IVec::from), size, cause }
+    }
+
+    #[test]
+    fn test_removal_causes() {
+        let removals = Arc::new(Mutex::new(Vec::new()));
+        let seen = removals.clone();
+        let cache = CacheConfig::new(20)
+            .temporary(true)
+            .removal_listener(move |removal| seen.lock().unwrap().push(removal), Delivery::Sync)
+            .build()
+            .unwrap();
+        cache.put(b"a", b"1234567890").unwrap();
+        cache.put(b"a", b"12345").unwrap();
+        cache.put(b"b", b"1234567890").unwrap();
+        cache.put(b"c", b"1234567890").unwrap();
+        cache.remove(b"b").unwrap();
+        cache.put_with_ttl(b"d", b"1", Duration::from_millis(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.get(b"d").unwrap(), None);
+        cache.clear().unwrap();
+        assert_eq!(
+            *removals.lock().unwrap(),
+            vec![
+                removal(b"a", Some(b"1234567890"), 10, RemovalCause::Replaced),
+                removal(b"a", Some(b"12345"), 5, RemovalCause::Evicted),
+                removal(b"b", Some(b"1234567890"), 10, RemovalCause::Explicit),
+                removal(b"d", Some(b"1"), 1, RemovalCause::Expired),
+                removal(b"c", Some(b"1234567890"), 10, RemovalCause::Cleared),
+            ]
+        );
+        // Nothing is delivered for removals that don't happen
+        cache.remove(b"missing").unwrap();
+        assert_eq!(removals.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_expired_removals() {
+        let removals = Arc::new(Mutex::new(Vec::new()));
+        let seen = removals.clone();
+        let cache = CacheConfig::new(100)
+            .temporary(true)
+            .removal_listener(move |removal: Removal| seen.lock().unwrap().push(removal.cause), Delivery::Sync)
+            .build()
+            .unwrap();
+        let ttl = Duration::from_millis(1);
+        cache.put_with_ttl(b"a", b"1", ttl).unwrap();
+        cache.put_with_ttl(b"b", b"1", ttl).unwrap();
+        cache.put_with_ttl(b"c", b"1", ttl).unwrap();
+        cache.put(b"d", b"1").unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        // Expired entries are gone as far as every write path is concerned
+        assert_eq!(cache.remove(b"a").unwrap(), None);
+        cache.put(b"b", b"2").unwrap();
+        assert_eq!(cache.clear().unwrap(), 2);
+        assert_eq!(
+            *removals.lock().unwrap(),
+            [RemovalCause::Expired, RemovalCause::Expired, RemovalCause::Cleared, RemovalCause::Expired, RemovalCause::Cleared]
+        );
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.inserts, stats.overwrites), (5, 0));
+    }
+
+    struct KeysOnly(Sender<Removal>);
+
+    impl RemovalListener for KeysOnly {
+        fn on_removal(&self, removal: Removal) {
+            self.0.send(removal).unwrap();
+        }
+
+        fn wants_values(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_queued_delivery() {
+        let (sender, removals) = mpsc::channel();
+        let cache = CacheConfig::new(10)
+            .temporary(true)
+            .removal_listener(KeysOnly(sender), Delivery::Queued)
+            .build()
+            .unwrap();
+        cache.put(b"a", &[0; 10]).unwrap();
+        cache.put(b"b", &[0; 10]).unwrap();
+        cache.purge_expired().unwrap();
+        cache.remove(b"b").unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(removals.recv_timeout(timeout).unwrap(), removal(b"a", None, 10, RemovalCause::Evicted));
+        assert_eq!(removals.recv_timeout(timeout).unwrap(), removal(b"b", None, 10, RemovalCause::Explicit));
+        // The delivery thread lets go of the listener once the cache is gone
+        drop(cache);
+        assert_eq!(removals.recv_timeout(timeout), Err(mpsc::RecvTimeoutError::Disconnected));
+    }
+}
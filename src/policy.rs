@@ -0,0 +1,976 @@
+//! Eviction policies: the order in which objects leave the cache when it is over budget.
+//!
+//! Every object has a rank, the key of its `OBJECT_LRU` record, and objects are evicted in
+//! ascending rank order. A policy decides the rank an object gets when it is written and when
+//! it is hit, and can keep state of its own in extra sled trees that are updated in the same
+//! transaction as the object.
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+use sled::transaction::TransactionalTree;
+use sled::Tree;
+
+use crate::admission::FrequencySketch;
+use crate::{decode_u64, EvictionVictim, IndexEntry, MultiLayerCache, Result, TinyLfu, TxResult};
+
+/// Transactional access to a policy's trees, handed to every `EvictionPolicy` hook.
+pub struct PolicyTx<'a> {
+    pub(crate) lru: &'a TransactionalTree,
+    pub(crate) index: &'a TransactionalTree,
+    pub(crate) state: &'a [TransactionalTree],
+    pub(crate) disk_budget: usize,
+}
+
+impl PolicyTx<'_> {
+    /// The cache's disk budget, for policies that size their segments by it.
+    pub fn disk_budget(&self) -> usize {
+        self.disk_budget
+    }
+
+    /// Move the object at `rank` to `new_rank`. Returns false, changing nothing, if no object
+    /// has that rank any more.
+    pub fn rerank(&self, key: &[u8], rank: &[u8], new_rank: &[u8]) -> TxResult<bool> {
+        let Some(entry) = self.index.get(key)? else {
+            return Ok(false);
+        };
+        let mut entry = IndexEntry::decode(&entry)?;
+        if entry.lru_key != rank {
+            return Ok(false);
+        }
+        let Some(key_and_size) = self.lru.remove(rank)? else {
+            return Ok(false);
+        };
+        self.lru.insert(new_rank, key_and_size)?;
+        entry.lru_key = new_rank.to_vec();
+        self.index.insert(key, entry.encode())?;
+        Ok(true)
+    }
+
+    /// A fresh id from sled's generator. Ids strictly increase, including across restarts.
+    pub fn next_id(&self) -> TxResult<u64> {
+        Ok(self.lru.generate_id()?)
+    }
+
+    /// The policy's `index`th tree, in the order returned by `EvictionPolicy::state_trees`.
+    ///
+    /// Panics if the policy declared fewer trees.
+    pub fn state(&self, index: usize) -> &TransactionalTree {
+        &self.state[index]
+    }
+}
+
+/// Run `f` on the state in `memory`, replacing it with `init()` if it is missing or of
+/// another type.
+fn with_memory<T: Any + Send, R>(memory: &PolicyMemory, init: impl FnOnce() -> Result<T>, f: impl FnOnce(&mut T) -> R) -> Result<R> {
+    // The state is only ever replaced whole, so it is intact even if a holder panicked
+    let mut memory = memory.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match memory.as_mut().and_then(|memory| memory.downcast_mut::<T>()) {
+        Some(memory) => Ok(f(memory)),
+        None => {
+            let mut fresh = init()?;
+            let result = f(&mut fresh);
+            *memory = Some(Box::new(fresh));
+            Ok(result)
+        }
+    }
+}
+
+/// In-memory state a policy keeps for one cache, see `PolicyMaintenance::memory`.
+pub(crate) type PolicyMemory = Mutex<Option<Box<dyn Any + Send>>>;
+
+/// Access to a cache's committed trees, handed to the hooks that run outside of transactions.
+///
+/// sled deadlocks if a tree is read directly inside a transaction, so policies that need to
+/// search their objects by rank do it here, then check what they found in a transaction of
+/// their own.
+pub struct PolicyMaintenance<'a> {
+    pub(crate) cache: &'a MultiLayerCache,
+}
+
+impl PolicyMaintenance<'_> {
+    /// The cache's disk budget, for policies that size their segments by it.
+    pub fn disk_budget(&self) -> usize {
+        self.cache.disk_budget
+    }
+
+    /// `OBJECT_LRU` as last committed.
+    pub fn lru(&self) -> &Tree {
+        &self.cache.trees.lru
+    }
+
+    /// The policy's `index`th tree as last committed.
+    ///
+    /// Panics if the policy declared fewer trees.
+    pub fn state(&self, index: usize) -> &Tree {
+        self.cache.trees.policy_state(index)
+    }
+
+    /// Run `f` on the policy's in-memory state for this cache, created by `init` on first use.
+    ///
+    /// The state is shared by the cache's clones and dropped with the last one, so anything
+    /// that should survive a restart has to be saved to a state tree as well, at the latest by
+    /// the state's `Drop` impl.
+    ///
+    /// Transactions hold sled's write lock, so it is never taken inside one; don't iterate
+    /// the cache's trees in `f` either, read what is needed first.
+    pub fn memory<T: Any + Send, R>(&self, init: impl FnOnce() -> Result<T>, f: impl FnOnce(&mut T) -> R) -> Result<R> {
+        with_memory(&self.cache.policy_memory, init, f)
+    }
+
+    /// Run `f` in a transaction over the cache's trees. It may be retried.
+    pub fn transaction<A>(&self, f: impl Fn(&PolicyTx) -> TxResult<A>) -> Result<A> {
+        self.cache.trees.transaction(|tx| f(&self.cache.policy_tx(tx)))
+    }
+}
+
+/// Chooses the order objects are evicted in.
+///
+/// The hooks that take a `PolicyTx` run inside the cache's transactions and may be retried, so
+/// they should only change state through it.
+pub trait EvictionPolicy: Debug + Send + Sync {
+    /// Names of the trees the policy keeps its state in. They are opened next to `OBJECT_LRU`,
+    /// in the cache's namespace if it has one.
+    fn state_trees(&self) -> &'static [&'static [u8]] {
+        &[]
+    }
+
+    /// Rank of an object being written.
+    fn rank_inserted(&self, tx: &PolicyTx, key: &[u8], size: usize) -> TxResult<Vec<u8>>;
+
+    /// Whether `get` hits are recorded in `OBJECT_INDEX` and ranked by `rank_hit`. Policies
+    /// that turn this off have hits served by plain reads, without writing to sled, and are
+    /// told about them by `hit` instead.
+    fn tracks_hits(&self) -> bool {
+        true
+    }
+
+    /// New rank of an object after a `get` hit, or `None` to leave it where it is. `hits`
+    /// counts this hit.
+    fn rank_hit(&self, tx: &PolicyTx, key: &[u8], rank: &[u8], size: usize, hits: u64) -> TxResult<Option<Vec<u8>>>;
+
+    /// Called when an object's rank is dropped, because it was `evicted` or for any other
+    /// reason: an overwrite, a removal or expiry.
+    fn removed(&self, _tx: &PolicyTx, _key: &[u8], _rank: &[u8], _size: usize, _evicted: bool) -> TxResult<()> {
+        Ok(())
+    }
+
+    /// Read eviction candidates from `OBJECT_LRU` until their sizes cover `excess`, skipping
+    /// `skip`. Returns the candidates and whether every object was read.
+    ///
+    /// This runs outside of any transaction, so nothing may be written to sled here, and may
+    /// be repeated if other writers take some of the candidates first. The cache checks the
+    /// candidates are still current before evicting them.
+    fn gather_victims(&self, cache: &PolicyMaintenance, skip: Option<&[u8]>, excess: usize) -> Result<(Vec<EvictionVictim>, bool)> {
+        gather_in_rank_order(cache.lru().iter(), skip, excess)
+    }
+
+    /// Called after a `get` hit on the object ranked `rank`, outside of any transaction, if
+    /// `tracks_hits` is off. Another writer may have replaced or removed the object since.
+    fn hit(&self, _cache: &PolicyMaintenance, _key: &[u8], _rank: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before every `get` and put of `key`, outside of any transaction, for policies
+    /// that track how often keys are asked for.
+    fn accessed(&self, _cache: &PolicyMaintenance, _key: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after a put, a `get` hit or an eviction commits, outside of any transaction.
+    fn maintain(&self, _cache: &PolicyMaintenance) -> Result<()> {
+        Ok(())
+    }
+
+    /// Check the policy's settings, as part of `CacheConfig::validate`.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Take victims from `entries`, `OBJECT_LRU` records, in order until their sizes cover `excess`.
+pub fn gather_in_rank_order(
+    mut entries: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
+    skip: Option<&[u8]>,
+    excess: usize,
+) -> Result<(Vec<EvictionVictim>, bool)> {
+    let mut total_evicted = 0;
+    let mut victims = Vec::new();
+    while total_evicted < excess {
+        if let Some(entry) = entries.next() {
+            let (lru_key, key_and_size) = entry?;
+            let victim = EvictionVictim::from_lru_entry(lru_key, &key_and_size)?;
+            if skip == Some(victim.key.as_slice()) {
+                continue;
+            }
+            total_evicted += victim.size;
+            victims.push(victim);
+        } else {
+            return Ok((victims, true));
+        }
+    }
+    Ok((victims, false))
+}
+
+/// Least recently used: every write and hit moves the object to the back.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn rank_inserted(&self, tx: &PolicyTx, _key: &[u8], _size: usize) -> TxResult<Vec<u8>> {
+        Ok(tx.next_id()?.to_be_bytes().to_vec())
+    }
+
+    fn rank_hit(&self, tx: &PolicyTx, _key: &[u8], _rank: &[u8], _size: usize, _hits: u64) -> TxResult<Option<Vec<u8>>> {
+        Ok(Some(tx.next_id()?.to_be_bytes().to_vec()))
+    }
+}
+
+/// First in, first out: objects leave in the order they were written, hits don't count.
+///
+/// Hits don't write to `OBJECT_LRU`, but still update the bookkeeping behind `entry_info`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fifo;
+
+impl EvictionPolicy for Fifo {
+    fn rank_inserted(&self, tx: &PolicyTx, _key: &[u8], _size: usize) -> TxResult<Vec<u8>> {
+        Ok(tx.next_id()?.to_be_bytes().to_vec())
+    }
+
+    fn rank_hit(&self, _tx: &PolicyTx, _key: &[u8], _rank: &[u8], _size: usize, _hits: u64) -> TxResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// CLOCK, as second-chance FIFO: approximately least recently used, without writing to sled
+/// on a hit.
+///
+/// A hit only sets the object's reference bit, which is kept in memory. Eviction sweeps the
+/// objects in the order they were written: referenced objects get a second chance and have
+/// their bit cleared, the first unreferenced ones are taken. The spared objects are moved to
+/// the back together, in one transaction after the eviction, so recency costs one batched
+/// write per object that survives a sweep instead of one per hit. Hits are served by plain
+/// reads, so they don't show up in `entry_info`.
+///
+/// Reference bits are lost on restart, so the first sweep after one evicts like `Fifo`. Ranks
+/// are 8-byte ids like those of `Lru`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Clock;
+
+/// The reference bits of one cache under `Clock`.
+#[derive(Default)]
+struct ClockFace {
+    /// Ranks of the objects hit since a sweep last passed them. Ranks of objects removed
+    /// since are never passed again, and are pruned by `maintain`.
+    referenced: HashSet<Vec<u8>>,
+    /// Objects passed over by a sweep, to be moved to the back by `maintain`
+    spared: Vec<EvictionVictim>,
+    /// Size of `referenced` at which it is next pruned
+    prune_at: usize,
+}
+
+impl Clock {
+    /// `OBJECT_LRU` records read per batch by a sweep, beyond those covering the excess
+    const SWEEP_BATCH: usize = 64;
+    const MIN_PRUNE_AT: usize = 1024;
+
+    fn with_face<R>(cache: &PolicyMaintenance, f: impl FnOnce(&mut ClockFace) -> R) -> Result<R> {
+        cache.memory(|| Ok(ClockFace::default()), f)
+    }
+
+    /// Forget the reference bits of objects that are gone, once there may be many of them.
+    fn prune(cache: &PolicyMaintenance) -> Result<()> {
+        if Self::with_face(cache, |face| face.referenced.len() < face.prune_at)? {
+            return Ok(());
+        }
+        let ranks = cache.lru().iter().keys().collect::<sled::Result<HashSet<_>>>()?;
+        // Objects written and hit since the ranks were read lose their bit
+        Self::with_face(cache, |face| {
+            face.referenced.retain(|rank| ranks.contains(rank.as_slice()));
+            face.prune_at = (face.referenced.len() * 2).max(Self::MIN_PRUNE_AT);
+        })
+    }
+}
+
+impl EvictionPolicy for Clock {
+    fn rank_inserted(&self, tx: &PolicyTx, _key: &[u8], _size: usize) -> TxResult<Vec<u8>> {
+        Ok(tx.next_id()?.to_be_bytes().to_vec())
+    }
+
+    fn tracks_hits(&self) -> bool {
+        false
+    }
+
+    fn rank_hit(&self, _tx: &PolicyTx, _key: &[u8], _rank: &[u8], _size: usize, _hits: u64) -> TxResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn hit(&self, cache: &PolicyMaintenance, _key: &[u8], rank: &[u8]) -> Result<()> {
+        Self::with_face(cache, |face| face.referenced.insert(rank.to_vec()))?;
+        Ok(())
+    }
+
+    fn gather_victims(&self, cache: &PolicyMaintenance, skip: Option<&[u8]>, excess: usize) -> Result<(Vec<EvictionVictim>, bool)> {
+        let mut victims = Vec::new();
+        let mut taken = HashSet::new();
+        let mut gathered = 0;
+        // Objects spared on the first turn are taken on the second if there is no other way
+        for _ in 0..2 {
+            let mut entries = cache.lru().iter();
+            while gathered < excess {
+                // The records are read before the reference bits are locked, sled's iterator
+                // takes locks of its own
+                let mut batch = Vec::new();
+                let mut batch_bytes = 0;
+                while gathered + batch_bytes < excess || batch.len() < Self::SWEEP_BATCH {
+                    let Some(entry) = entries.next() else {
+                        break;
+                    };
+                    let (rank, key_and_size) = entry?;
+                    let victim = EvictionVictim::from_lru_entry(rank, &key_and_size)?;
+                    if skip == Some(victim.key.as_slice()) || taken.contains(&victim.lru_key) {
+                        continue;
+                    }
+                    batch_bytes += victim.size;
+                    batch.push(victim);
+                }
+                if batch.is_empty() {
+                    break;
+                }
+                Self::with_face(cache, |face| {
+                    for victim in batch {
+                        if gathered >= excess {
+                            break;
+                        }
+                        if face.referenced.remove(victim.lru_key.as_ref()) {
+                            face.spared.push(victim);
+                            continue;
+                        }
+                        gathered += victim.size;
+                        taken.insert(victim.lru_key.clone());
+                        victims.push(victim);
+                    }
+                })?;
+            }
+            if gathered >= excess {
+                return Ok((victims, false));
+            }
+        }
+        Ok((victims, true))
+    }
+
+    /// Move the objects spared by the last sweeps to the back, in the order they were passed,
+    /// and prune the reference bits.
+    fn maintain(&self, cache: &PolicyMaintenance) -> Result<()> {
+        Self::prune(cache)?;
+        let spared = Self::with_face(cache, |face| std::mem::take(&mut face.spared))?;
+        if spared.is_empty() {
+            return Ok(());
+        }
+        // Objects evicted or hit since are skipped
+        cache.transaction(|tx| {
+            for object in &spared {
+                tx.rerank(&object.key, &object.lru_key, &tx.next_id()?.to_be_bytes())?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Least frequently used with dynamic aging (LFU-DA).
+///
+/// An object's priority is the cache age plus its hit count, and the least valuable object
+/// goes first, oldest first among equals. The cache age is the priority of the last evicted
+/// object, so objects that were popular once but stopped being hit eventually fall below
+/// newcomers instead of staying forever. The age is kept in the `lfu_age` tree.
+///
+/// Ranks are the big-endian priority followed by an id, 16 bytes in all.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lfu;
+
+impl Lfu {
+    pub const AGE_TREE: &'static [u8] = b"lfu_age";
+    const AGE_KEY: &'static [u8] = b"current";
+
+    fn age(tx: &PolicyTx) -> TxResult<u64> {
+        match tx.state(0).get(Self::AGE_KEY)? {
+            Some(age) => Ok(decode_u64(&age, "LFU cache age")?),
+            None => Ok(0),
+        }
+    }
+
+    fn rank(tx: &PolicyTx, priority: u64) -> TxResult<Vec<u8>> {
+        let mut rank = priority.to_be_bytes().to_vec();
+        rank.extend_from_slice(&tx.next_id()?.to_be_bytes());
+        Ok(rank)
+    }
+}
+
+impl EvictionPolicy for Lfu {
+    fn state_trees(&self) -> &'static [&'static [u8]] {
+        &[Self::AGE_TREE]
+    }
+
+    fn rank_inserted(&self, tx: &PolicyTx, _key: &[u8], _size: usize) -> TxResult<Vec<u8>> {
+        Self::rank(tx, Self::age(tx)?.saturating_add(1))
+    }
+
+    fn rank_hit(&self, tx: &PolicyTx, _key: &[u8], _rank: &[u8], _size: usize, hits: u64) -> TxResult<Option<Vec<u8>>> {
+        Ok(Some(Self::rank(tx, Self::age(tx)?.saturating_add(hits).saturating_add(1))?))
+    }
+
+    fn removed(&self, tx: &PolicyTx, _key: &[u8], rank: &[u8], _size: usize, evicted: bool) -> TxResult<()> {
+        // Ranks of any other length were given by another policy and carry no priority
+        if !evicted || rank.len() != 16 {
+            return Ok(());
+        }
+        let priority = decode_u64(&rank[..8], "LFU priority")?;
+        if priority > Self::age(tx)? {
+            tx.state(0).insert(Self::AGE_KEY, &priority.to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// A rank of `segment` followed by a fresh id, as given by the segmented policies.
+fn segmented_rank(tx: &PolicyTx, segment: u8) -> TxResult<Vec<u8>> {
+    let mut rank = vec![segment];
+    rank.extend_from_slice(&tx.next_id()?.to_be_bytes());
+    Ok(rank)
+}
+
+/// The segment byte of a rank given by `segmented_rank`.
+fn rank_segment(rank: &[u8]) -> Option<u8> {
+    match rank {
+        [segment, _, _, _, _, _, _, _, _] => Some(*segment),
+        _ => None,
+    }
+}
+
+/// Share of `total` given by `percent`, capped at all of it.
+fn percent_of(total: usize, percent: u8) -> u64 {
+    (total as u128 * percent.min(100) as u128 / 100) as u64
+}
+
+/// Byte count of a segment, kept under `key` in the policy's first state tree.
+fn segment_bytes(tx: &PolicyTx, key: &[u8]) -> TxResult<u64> {
+    match tx.state(0).get(key)? {
+        Some(bytes) => Ok(decode_u64(&bytes, "segment byte count")?),
+        None => Ok(0),
+    }
+}
+
+fn set_segment_bytes(tx: &PolicyTx, key: &[u8], bytes: u64) -> TxResult<()> {
+    tx.state(0).insert(key, &bytes.to_be_bytes())?;
+    Ok(())
+}
+
+fn add_segment_bytes(tx: &PolicyTx, key: &[u8], size: usize) -> TxResult<()> {
+    set_segment_bytes(tx, key, segment_bytes(tx, key)?.saturating_add(size as u64))
+}
+
+fn sub_segment_bytes(tx: &PolicyTx, key: &[u8], size: usize) -> TxResult<()> {
+    set_segment_bytes(tx, key, segment_bytes(tx, key)?.saturating_sub(size as u64))
+}
+
+/// `segment_bytes` as last committed.
+fn committed_segment_bytes(cache: &PolicyMaintenance, key: &[u8]) -> Result<u64> {
+    match cache.state(0).get(key)? {
+        Some(bytes) => decode_u64(&bytes, "segment byte count"),
+        None => Ok(0),
+    }
+}
+
+/// The oldest objects of `segment`, by committed rank, until their sizes cover `bytes`.
+fn oldest_in_segment(cache: &PolicyMaintenance, segment: u8, bytes: u64) -> Result<Vec<EvictionVictim>> {
+    let entries = cache.lru().range([segment]..[segment + 1]);
+    Ok(gather_in_rank_order(entries, None, bytes.try_into().unwrap_or(usize::MAX))?.0)
+}
+
+/// Move the oldest objects of `segment` to the back of `to` until the byte count under
+/// `bytes_key` is within `capacity`.
+///
+/// Objects hit or removed since they were read are skipped; the next call catches up.
+fn shrink_segment(cache: &PolicyMaintenance, bytes_key: &[u8], segment: u8, to: u8, capacity: u64) -> Result<()> {
+    let bytes = committed_segment_bytes(cache, bytes_key)?;
+    if bytes <= capacity {
+        return Ok(());
+    }
+    let candidates = oldest_in_segment(cache, segment, bytes - capacity)?;
+    cache.transaction(|tx| {
+        let mut bytes = segment_bytes(tx, bytes_key)?;
+        for victim in &candidates {
+            if bytes <= capacity {
+                break;
+            }
+            if tx.rerank(&victim.key, &victim.lru_key, &segmented_rank(tx, to)?)? {
+                bytes = bytes.saturating_sub(victim.size as u64);
+            }
+        }
+        set_segment_bytes(tx, bytes_key, bytes)
+    })
+}
+
+/// Segmented LRU: scan resistant, objects have to be hit to be protected.
+///
+/// New objects start in a probation segment and move to a protected segment when they are
+/// hit. Probation is always evicted first, so a scan that reads every key once only churns
+/// probation. The protected segment holds at most `protected_percent` of the disk budget;
+/// after a promotion pushes it over, its least recently used objects drop back to the front
+/// of probation.
+///
+/// Ranks are a segment byte, `PROBATION` or `PROTECTED`, followed by an id. The bytes held
+/// in the protected segment are kept in the `slru_segments` tree.
+#[derive(Debug, Clone, Copy)]
+pub struct Slru {
+    pub protected_percent: u8,
+}
+
+impl Default for Slru {
+    fn default() -> Self {
+        Self { protected_percent: 80 }
+    }
+}
+
+impl Slru {
+    pub const PROBATION: u8 = 0;
+    pub const PROTECTED: u8 = 1;
+    pub const SEGMENTS_TREE: &'static [u8] = b"slru_segments";
+    const PROTECTED_BYTES_KEY: &'static [u8] = b"protected_bytes";
+
+    /// The segment of `rank`, if it was given by this policy.
+    fn segment(rank: &[u8]) -> Option<u8> {
+        rank_segment(rank).filter(|segment| *segment <= Self::PROTECTED)
+    }
+}
+
+impl EvictionPolicy for Slru {
+    fn state_trees(&self) -> &'static [&'static [u8]] {
+        &[Self::SEGMENTS_TREE]
+    }
+
+    fn rank_inserted(&self, tx: &PolicyTx, _key: &[u8], _size: usize) -> TxResult<Vec<u8>> {
+        segmented_rank(tx, Self::PROBATION)
+    }
+
+    fn rank_hit(&self, tx: &PolicyTx, _key: &[u8], rank: &[u8], size: usize, _hits: u64) -> TxResult<Option<Vec<u8>>> {
+        let segment = Self::segment(rank).unwrap_or(Self::PROBATION);
+        if segment == Self::PROTECTED || size as u64 > percent_of(tx.disk_budget(), self.protected_percent) {
+            return Ok(Some(segmented_rank(tx, segment)?));
+        }
+        // The segment may now be over capacity until `maintain` demotes from it
+        add_segment_bytes(tx, Self::PROTECTED_BYTES_KEY, size)?;
+        Ok(Some(segmented_rank(tx, Self::PROTECTED)?))
+    }
+
+    fn removed(&self, tx: &PolicyTx, _key: &[u8], rank: &[u8], size: usize, _evicted: bool) -> TxResult<()> {
+        if Self::segment(rank) == Some(Self::PROTECTED) {
+            sub_segment_bytes(tx, Self::PROTECTED_BYTES_KEY, size)?;
+        }
+        Ok(())
+    }
+
+    /// Demote the least recently used protected objects to probation until the segment is back
+    /// within capacity.
+    fn maintain(&self, cache: &PolicyMaintenance) -> Result<()> {
+        let capacity = percent_of(cache.disk_budget(), self.protected_percent);
+        shrink_segment(cache, Self::PROTECTED_BYTES_KEY, Self::PROTECTED, Self::PROBATION, capacity)
+    }
+}
+
+/// Window TinyLFU, after Caffeine: frequency decides which objects stay.
+///
+/// New objects enter a small LRU window. Objects pushed out of the window compete with the
+/// oldest object on probation in the main region, a segmented LRU like `Slru`: whichever of
+/// the two a frequency sketch estimates was asked for less is condemned, and condemned objects
+/// are evicted first. The window lets bursts of new keys build up a frequency before they
+/// have to compete, and the sketch keeps one-off keys from pushing out popular ones.
+///
+/// Every `get` and put counts towards the sketch, which is kept in memory and saved after
+/// `sketch.persist_every` accesses. Ranks are a segment byte followed by an id; the segments
+/// are evicted in the order `CONDEMNED`, `PROBATION`, `WINDOW`, `PROTECTED`. The sketch and
+/// the bytes held by the window and the protected segment are kept in the `wtinylfu_state`
+/// tree.
+#[derive(Debug, Clone, Copy)]
+pub struct WTinyLfu {
+    /// Share of the disk budget held by the window
+    pub window_percent: u8,
+    /// Share of the main region, the rest of the budget, held by its protected segment
+    pub protected_percent: u8,
+    pub sketch: TinyLfu,
+}
+
+impl Default for WTinyLfu {
+    fn default() -> Self {
+        Self { window_percent: 1, protected_percent: 80, sketch: TinyLfu::default() }
+    }
+}
+
+/// The in-memory sketch of one cache under `WTinyLfu`, saved when the last clone of the cache
+/// is dropped.
+struct SketchMemory {
+    sketch: FrequencySketch,
+    /// Accesses since the sketch was last saved
+    unsaved: u64,
+    state: Tree,
+}
+
+impl Drop for SketchMemory {
+    fn drop(&mut self) {
+        // Nowhere to report a failure from here; accesses since the last save are lost
+        if self.unsaved > 0 && self.state.insert(WTinyLfu::SKETCH_KEY, self.sketch.encode()).is_ok() {
+            let _ = self.state.flush();
+        }
+    }
+}
+
+impl WTinyLfu {
+    pub const CONDEMNED: u8 = 0;
+    pub const PROBATION: u8 = 1;
+    pub const WINDOW: u8 = 2;
+    pub const PROTECTED: u8 = 3;
+    pub const STATE_TREE: &'static [u8] = b"wtinylfu_state";
+    const WINDOW_BYTES_KEY: &'static [u8] = b"window_bytes";
+    const PROTECTED_BYTES_KEY: &'static [u8] = b"protected_bytes";
+    const SKETCH_KEY: &'static [u8] = b"sketch";
+
+    /// The segment of `rank`, if it was given by this policy.
+    fn segment(rank: &[u8]) -> Option<u8> {
+        rank_segment(rank).filter(|segment| *segment <= Self::PROTECTED)
+    }
+
+    /// Capacities of the window and the protected segment.
+    fn capacities(&self, disk_budget: usize) -> (u64, u64) {
+        let window = percent_of(disk_budget, self.window_percent);
+        let main = (disk_budget as u64).saturating_sub(window);
+        (window, percent_of(main.try_into().unwrap_or(usize::MAX), self.protected_percent))
+    }
+
+    /// Run `f` on the cache's sketch, loading the saved one on first use.
+    fn with_sketch<R>(&self, cache: &PolicyMaintenance, f: impl FnOnce(&mut SketchMemory) -> R) -> Result<R> {
+        let load = || {
+            let state = cache.state(0).clone();
+            let sketch = FrequencySketch::load(state.get(Self::SKETCH_KEY)?.as_deref(), self.sketch.expected_objects)?;
+            Ok(SketchMemory { sketch, unsaved: 0, state })
+        };
+        cache.memory(load, f)
+    }
+
+    /// Pit the oldest window objects over the window's capacity against the oldest objects on
+    /// probation, condemning the less frequently used of each pair.
+    fn drain_window(&self, cache: &PolicyMaintenance, capacity: u64) -> Result<()> {
+        let window = committed_segment_bytes(cache, Self::WINDOW_BYTES_KEY)?;
+        if window <= capacity {
+            return Ok(());
+        }
+        let candidates = oldest_in_segment(cache, Self::WINDOW, window - capacity)?;
+        let probation = cache.lru().range([Self::PROBATION]..[Self::PROBATION + 1]).take(candidates.len());
+        let (rivals, _) = gather_in_rank_order(probation, None, usize::MAX)?;
+        let moves = self.with_sketch(cache, |memory| {
+            let mut rivals = rivals.into_iter().peekable();
+            let mut moves = Vec::new();
+            for candidate in candidates {
+                match rivals.peek() {
+                    Some(rival) if memory.sketch.estimate(&candidate.key) <= memory.sketch.estimate(&rival.key) => {
+                        moves.push((candidate, Self::CONDEMNED));
+                    }
+                    _ => {
+                        moves.extend(rivals.next().map(|rival| (rival, Self::CONDEMNED)));
+                        moves.push((candidate, Self::PROBATION));
+                    }
+                }
+            }
+            moves
+        })?;
+        // Objects hit or removed since they were read are skipped; the next put catches up
+        cache.transaction(|tx| {
+            let mut window = segment_bytes(tx, Self::WINDOW_BYTES_KEY)?;
+            for (object, segment) in &moves {
+                let moved = tx.rerank(&object.key, &object.lru_key, &segmented_rank(tx, *segment)?)?;
+                if moved && Self::segment(&object.lru_key) == Some(Self::WINDOW) {
+                    window = window.saturating_sub(object.size as u64);
+                }
+            }
+            set_segment_bytes(tx, Self::WINDOW_BYTES_KEY, window)
+        })
+    }
+}
+
+impl EvictionPolicy for WTinyLfu {
+    fn state_trees(&self) -> &'static [&'static [u8]] {
+        &[Self::STATE_TREE]
+    }
+
+    fn rank_inserted(&self, tx: &PolicyTx, _key: &[u8], size: usize) -> TxResult<Vec<u8>> {
+        add_segment_bytes(tx, Self::WINDOW_BYTES_KEY, size)?;
+        segmented_rank(tx, Self::WINDOW)
+    }
+
+    fn rank_hit(&self, tx: &PolicyTx, _key: &[u8], rank: &[u8], size: usize, _hits: u64) -> TxResult<Option<Vec<u8>>> {
+        let segment = Self::segment(rank).unwrap_or(Self::PROBATION);
+        let (_, protected_capacity) = self.capacities(tx.disk_budget());
+        if matches!(segment, Self::WINDOW | Self::PROTECTED) || size as u64 > protected_capacity {
+            return Ok(Some(segmented_rank(tx, segment.max(Self::PROBATION))?));
+        }
+        // Hits on probation, including condemned objects not evicted yet, earn protection
+        add_segment_bytes(tx, Self::PROTECTED_BYTES_KEY, size)?;
+        Ok(Some(segmented_rank(tx, Self::PROTECTED)?))
+    }
+
+    fn removed(&self, tx: &PolicyTx, _key: &[u8], rank: &[u8], size: usize, _evicted: bool) -> TxResult<()> {
+        match Self::segment(rank) {
+            Some(Self::WINDOW) => sub_segment_bytes(tx, Self::WINDOW_BYTES_KEY, size),
+            Some(Self::PROTECTED) => sub_segment_bytes(tx, Self::PROTECTED_BYTES_KEY, size),
+            _ => Ok(()),
+        }
+    }
+
+    fn accessed(&self, cache: &PolicyMaintenance, key: &[u8]) -> Result<()> {
+        let due = self.with_sketch(cache, |memory| {
+            memory.sketch.increment(key);
+            memory.unsaved += 1;
+            (memory.unsaved >= self.sketch.persist_every).then(|| {
+                memory.unsaved = 0;
+                memory.sketch.encode()
+            })
+        })?;
+        if let Some(encoded) = due {
+            cache.state(0).insert(Self::SKETCH_KEY, encoded)?;
+        }
+        Ok(())
+    }
+
+    /// Demote protected objects over capacity to probation, then drain the window into the
+    /// main region.
+    fn maintain(&self, cache: &PolicyMaintenance) -> Result<()> {
+        let (window_capacity, protected_capacity) = self.capacities(cache.disk_budget());
+        shrink_segment(cache, Self::PROTECTED_BYTES_KEY, Self::PROTECTED, Self::PROBATION, protected_capacity)?;
+        self.drain_window(cache, window_capacity)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.sketch.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{reopen, TestDir};
+    use crate::{CacheConfig, MultiLayerCache};
+
+    fn cache(policy: impl EvictionPolicy + 'static) -> MultiLayerCache {
+        CacheConfig::new(30).temporary(true).eviction_policy(policy).build().unwrap()
+    }
+
+    #[test]
+    fn test_lru() {
+        let cache = cache(Lru);
+        cache.put(b"a", &[0; 10]).unwrap();
+        cache.put(b"b", &[0; 10]).unwrap();
+        cache.put(b"c", &[0; 10]).unwrap();
+        cache.get(b"a").unwrap();
+        cache.put(b"d", &[0; 10]).unwrap();
+        assert!(cache.contains_key(b"a").unwrap());
+        assert!(!cache.contains_key(b"b").unwrap());
+    }
+
+    #[test]
+    fn test_fifo() {
+        let cache = cache(Fifo);
+        cache.put(b"a", &[0; 10]).unwrap();
+        cache.put(b"b", &[0; 10]).unwrap();
+        cache.put(b"c", &[0; 10]).unwrap();
+        let rank = cache.entry_info(b"a").unwrap().unwrap().lru_position;
+        cache.get(b"a").unwrap();
+        // A hit leaves the rank alone but is still counted
+        let info = cache.entry_info(b"a").unwrap().unwrap();
+        assert_eq!((info.lru_position, info.hits), (rank, 1));
+        cache.put(b"d", &[0; 10]).unwrap();
+        assert!(!cache.contains_key(b"a").unwrap());
+        assert!(cache.contains_key(b"b").unwrap());
+    }
+
+    #[test]
+    fn test_clock() {
+        let cache = cache(Clock);
+        cache.put(b"a", &[0; 10]).unwrap();
+        cache.put(b"b", &[0; 10]).unwrap();
+        cache.put(b"c", &[0; 10]).unwrap();
+        let trees = [&cache.trees.lru, &cache.trees.index];
+        let snapshot = || trees.map(|tree| tree.iter().collect::<sled::Result<Vec<_>>>().unwrap());
+        let before = snapshot();
+        // A hit leaves OBJECT_LRU and OBJECT_INDEX alone
+        assert!(cache.get(b"a").unwrap().is_some());
+        assert_eq!(snapshot(), before);
+        assert_eq!(cache.stats().unwrap().hits, 1);
+
+        // The referenced object gets a second chance at the back, behind the one just written
+        cache.put(b"d", &[0; 10]).unwrap();
+        assert!(cache.contains_key(b"a").unwrap());
+        assert!(!cache.contains_key(b"b").unwrap());
+        for (put, evicted) in [(b"e", b"c"), (b"f", b"d"), (b"g", b"a")] {
+            cache.put(put, &[0; 10]).unwrap();
+            assert!(!cache.contains_key(evicted).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_clock_concurrent() {
+        let cache = CacheConfig::new(2000).temporary(true).eviction_policy(Clock).build().unwrap();
+        let threads = (0..8u32).map(|thread_id| {
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                for n in 0..1000u32 {
+                    // Each thread's keys overlap with the next thread's
+                    let key = (thread_id * 500 + n % 700).to_be_bytes();
+                    cache.put(&key, &[thread_id as u8; 20]).unwrap();
+                    cache.get(&key).unwrap();
+                    if n % 3 == 0 {
+                        cache.remove(&key).unwrap();
+                    }
+                }
+            })
+        });
+        for thread in threads.collect::<Vec<_>>() {
+            thread.join().unwrap();
+        }
+        assert!(cache.get_disk_usage().unwrap() <= 2000);
+        // The bits of removed objects don't pile up
+        let referenced = with_memory(&cache.policy_memory, || Ok(ClockFace::default()), |face| face.referenced.len()).unwrap();
+        assert!(referenced < 2 * Clock::MIN_PRUNE_AT, "{} reference bits kept", referenced);
+    }
+
+    #[test]
+    fn test_lfu() {
+        let cache = cache(Lfu);
+        cache.put(b"a", &[0; 10]).unwrap();
+        cache.put(b"b", &[0; 10]).unwrap();
+        cache.put(b"c", &[0; 10]).unwrap();
+        for _ in 0..3 {
+            cache.get(b"a").unwrap();
+        }
+        cache.get(b"b").unwrap();
+        cache.put(b"d", &[0; 10]).unwrap();
+        assert!(!cache.contains_key(b"c").unwrap());
+        let age_tree = cache.db.open_tree(Lfu::AGE_TREE).unwrap();
+        assert_eq!(age_tree.get(Lfu::AGE_KEY).unwrap().unwrap(), 1u64.to_be_bytes());
+
+        // A stream of one-off keys doesn't flush the popular object right away...
+        for n in 0..3u8 {
+            cache.put(&[n], &[0; 10]).unwrap();
+        }
+        assert!(cache.contains_key(b"a").unwrap());
+        // ...but as the cache ages it stops outranking newcomers it is no longer hit more than
+        for n in 3..10u8 {
+            cache.put(&[n], &[0; 10]).unwrap();
+        }
+        assert!(!cache.contains_key(b"a").unwrap());
+    }
+
+    /// Hit a working set of four objects, then scan fifty objects once each.
+    fn scan(cache: &MultiLayerCache) -> usize {
+        let cache = cache.clone();
+        for n in 0..4u8 {
+            cache.put(&[b'h', n], &[0; 10]).unwrap();
+            cache.get(&[b'h', n]).unwrap();
+        }
+        for n in 0..50u8 {
+            cache.put(&[b's', n], &[0; 10]).unwrap();
+        }
+        (0..4u8).filter(|&n| cache.contains_key(&[b'h', n]).unwrap()).count()
+    }
+
+    fn segment_of(cache: &MultiLayerCache, key: &[u8]) -> u8 {
+        let entry = cache.trees.index.get(key).unwrap().unwrap();
+        IndexEntry::decode(&entry).unwrap().lru_key[0]
+    }
+
+    #[test]
+    fn test_slru_scan_resistance() {
+        let config = CacheConfig::new(100).temporary(true);
+        assert_eq!(scan(&config.clone().build().unwrap()), 0);
+        assert_eq!(scan(&config.eviction_policy(Slru::default()).build().unwrap()), 4);
+    }
+
+    #[test]
+    fn test_slru_demotion() {
+        let cache = CacheConfig::new(100).temporary(true).eviction_policy(Slru::default()).build().unwrap();
+        let segments_tree = cache.db.open_tree(Slru::SEGMENTS_TREE).unwrap();
+        let protected_bytes = || decode_u64(&segments_tree.get(Slru::PROTECTED_BYTES_KEY).unwrap().unwrap(), "").unwrap();
+        for n in 0..9u8 {
+            cache.put(&[n], &[0; 10]).unwrap();
+            assert_eq!(segment_of(&cache, &[n]), Slru::PROBATION);
+            cache.get(&[n]).unwrap();
+            assert_eq!(segment_of(&cache, &[n]), Slru::PROTECTED);
+        }
+        // The ninth promotion pushed the least recently used protected object back to probation
+        assert_eq!(protected_bytes(), 80);
+        assert_eq!(segment_of(&cache, &[0]), Slru::PROBATION);
+        assert_eq!(segment_of(&cache, &[1]), Slru::PROTECTED);
+        // Demoted objects are the first to go
+        cache.put(b"new", &[0; 20]).unwrap();
+        assert!(!cache.contains_key(&[0]).unwrap());
+        assert!(cache.contains_key(&[1]).unwrap());
+        cache.remove(&[1]).unwrap();
+        assert_eq!(protected_bytes(), 70);
+    }
+
+    /// Hit ratio of `cache` on a trace of gets drawn from a Zipf distribution over a thousand
+    /// keys, putting every miss.
+    fn zipf_hit_ratio(cache: &MultiLayerCache) -> f64 {
+        let weights: Vec<f64> = (1..=1000).map(|rank| 1.0 / rank as f64).collect();
+        let total: f64 = weights.iter().sum();
+        let cumulative: Vec<f64> = weights
+            .iter()
+            .scan(0.0, |sum, weight| {
+                *sum += weight / total;
+                Some(*sum)
+            })
+            .collect();
+        // xorshift64, so the trace is the same on every run
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..10_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let sample = (state >> 11) as f64 / (1u64 << 53) as f64;
+            let key = (cumulative.partition_point(|&sum| sum < sample) as u32).to_be_bytes();
+            if cache.get(&key).unwrap().is_none() {
+                cache.put(&key, &[0; 10]).unwrap();
+            }
+        }
+        cache.stats().unwrap().hit_ratio()
+    }
+
+    #[test]
+    fn test_wtinylfu_zipf() {
+        let config = CacheConfig::new(1000).temporary(true);
+        let lru = zipf_hit_ratio(&config.clone().build().unwrap());
+        let cache = config.eviction_policy(WTinyLfu::default()).build().unwrap();
+        let wtinylfu = zipf_hit_ratio(&cache);
+        assert!(wtinylfu > lru + 0.05, "W-TinyLFU hit ratio {} isn't clearly better than LRU's {}", wtinylfu, lru);
+        // The sketch was saved along the way
+        let state_tree = cache.db.open_tree(WTinyLfu::STATE_TREE).unwrap();
+        assert!(state_tree.contains_key(WTinyLfu::SKETCH_KEY).unwrap());
+    }
+
+    #[test]
+    fn test_wtinylfu_sketch_saved_on_drop() {
+        let sled_path = TestDir::new();
+        let policy = WTinyLfu { sketch: TinyLfu { expected_objects: 100, persist_every: 1000 }, ..WTinyLfu::default() };
+        let open = || reopen(|| CacheConfig::new(30).path(&sled_path).eviction_policy(policy).build());
+        let cache = open();
+        cache.put(b"a", &[0; 10]).unwrap();
+        let clone = cache.clone();
+        drop(cache);
+        clone.get(b"a").unwrap();
+        drop(clone);
+
+        let cache = open();
+        let saved = cache.trees.policy_state(0).get(WTinyLfu::SKETCH_KEY).unwrap().unwrap();
+        assert_eq!(FrequencySketch::load(Some(&saved), 100).unwrap().estimate(b"a"), 2);
+    }
+}
@@ -0,0 +1,326 @@
+//! Several named caches in one sled database, each with its own disk budget.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+use sled::Db;
+
+use crate::{BudgetEnforcement, CacheConfig, CreedmoorError, MultiLayerCache, Result};
+
+/// Namespaces open in a registry and the cap on their combined usage.
+pub(crate) struct GlobalBudget {
+    cap: usize,
+    /// One handle per namespace, without a link back to this budget so nothing is kept alive
+    /// in a cycle.
+    namespaces: RwLock<BTreeMap<String, MultiLayerCache>>,
+    /// Held by the put that is evicting for the cap
+    evicting: Mutex<()>,
+}
+
+impl GlobalBudget {
+    fn new(cap: usize) -> Self {
+        Self { cap, namespaces: RwLock::new(BTreeMap::new()), evicting: Mutex::new(()) }
+    }
+
+    // The map is only ever inserted into, so it is intact even if a holder panicked
+    fn namespaces(&self) -> RwLockReadGuard<'_, BTreeMap<String, MultiLayerCache>> {
+        self.namespaces.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn namespaces_mut(&self) -> RwLockWriteGuard<'_, BTreeMap<String, MultiLayerCache>> {
+        self.namespaces.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn usage<'a>(namespaces: impl IntoIterator<Item = &'a MultiLayerCache>) -> Result<usize> {
+        let mut total = 0usize;
+        for cache in namespaces {
+            total = total.saturating_add(cache.get_disk_usage()?);
+        }
+        Ok(total)
+    }
+
+    /// Bring the namespaces' combined usage back under the cap, unless another put is already
+    /// doing so. Returns the number of bytes evicted.
+    ///
+    /// Called after every put, so it only reads the usage counters, under the shared lock,
+    /// until the cap is exceeded.
+    pub(crate) fn enforce(&self) -> Result<usize> {
+        let mut evicted = 0;
+        while Self::usage(self.namespaces().values())? > self.cap {
+            let _evicting = match self.evicting.try_lock() {
+                Ok(evicting) => evicting,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                // The evicting put checks the usage again once it is done, so it sees ours too
+                Err(TryLockError::WouldBlock) => break,
+            };
+            let freed = self.evict_over_cap()?;
+            if freed == 0 {
+                break;
+            }
+            evicted += freed;
+        }
+        Ok(evicted)
+    }
+
+    /// Evict the lowest ranked objects across all namespaces until their combined usage is
+    /// back under the cap. Returns the number of bytes evicted.
+    ///
+    /// Ranks are built from the database-wide id generator, so under `Lru` the smallest first
+    /// `OBJECT_LRU` key among the namespaces belongs to the least recently used object of them all.
+    fn evict_over_cap(&self) -> Result<usize> {
+        // Evicting delivers removals, and a listener may call back into the registry, so the
+        // map isn't held while evicting
+        let namespaces = self.namespaces().values().cloned().collect::<Vec<_>>();
+        let mut usage = Self::usage(&namespaces)?;
+        let mut evicted = 0;
+        while usage > self.cap {
+            let mut oldest: Option<(sled::IVec, &MultiLayerCache)> = None;
+            for cache in &namespaces {
+                if let Some((lru_key, _)) = cache.trees.lru.first()? {
+                    if oldest.as_ref().is_none_or(|(oldest_key, _)| lru_key < *oldest_key) {
+                        oldest = Some((lru_key, cache));
+                    }
+                }
+            }
+            let Some((_, cache)) = oldest else {
+                break;
+            };
+            let freed = cache.evict(1)?;
+            if freed == 0 {
+                break;
+            }
+            evicted += freed;
+            usage = Self::usage(&namespaces)?;
+        }
+        Ok(evicted)
+    }
+}
+
+/// Named caches sharing one sled database.
+///
+/// Each namespace keeps its objects in its own trees, named after the namespace, and is held
+/// to its own disk budget like a standalone `MultiLayerCache`. The `disk_budget` of the
+/// registry's `CacheConfig` caps the namespaces' combined usage: after a put pushes them over
+/// it, the lowest ranked objects across all namespaces are evicted. Pass `usize::MAX` to
+/// only hold namespaces to their own budgets.
+///
+/// ```no_run
+/// use creedmoor::{CacheConfig, CacheRegistry};
+///
+/// let registry = CacheRegistry::open(CacheConfig::new(100 * 1024 * 1024).path("/var/cache/app"))?;
+/// let thumbnails = registry.namespace("thumbnails", 80 * 1024 * 1024)?;
+/// let responses = registry.namespace("responses", 40 * 1024 * 1024)?;
+/// thumbnails.put(b"cat.png", b"...")?;
+/// responses.put(b"/index.html", b"...")?;
+/// # Ok::<(), creedmoor::CreedmoorError>(())
+/// ```
+#[derive(Clone)]
+pub struct CacheRegistry {
+    db: Db,
+    config: CacheConfig,
+    global_budget: Arc<GlobalBudget>,
+}
+
+impl CacheRegistry {
+    /// Separates the namespace from the tree name, namespaces may not contain it.
+    pub const SEPARATOR: char = '/';
+
+    /// Open the sled database described by `config`.
+    ///
+    /// Namespaces found in the database count towards the global cap straight away, even
+    /// before they are opened again with `namespace`. Every namespace shares the size model,
+    /// usage drift handling, time-to-live, maximum object size, eviction policy and removal
+    /// listener from `config`.
+    pub fn open(config: CacheConfig) -> Result<Self> {
+        config.validate()?;
+        if config.budget_enforcement != BudgetEnforcement::Logical {
+            // sled only reports the size of the whole database, not of each namespace
+            return Err(CreedmoorError::InvalidConfig(
+                "namespaces only support BudgetEnforcement::Logical".to_string(),
+            ));
+        }
+        let db = config.sled_config().open()?;
+        let registry = Self {
+            db,
+            global_budget: Arc::new(GlobalBudget::new(config.disk_budget)),
+            config,
+        };
+        let suffix = MultiLayerCache::tree_name(Some(""), MultiLayerCache::DISK_USAGE_TREE);
+        for tree_name in registry.db.tree_names() {
+            let Some(name) = tree_name.strip_suffix(suffix.as_slice()) else {
+                continue;
+            };
+            let Ok(name) = std::str::from_utf8(name) else {
+                continue;
+            };
+            let config = registry.namespace_config(registry.config.disk_budget)?;
+            let cache = MultiLayerCache::open(registry.db.clone(), &config, Some(name))?;
+            registry.global_budget.namespaces_mut().insert(name.to_string(), cache);
+        }
+        Ok(registry)
+    }
+
+    /// Open the namespace `name`, creating it if needed, held to `disk_budget` bytes.
+    ///
+    /// Opening a namespace again returns another handle to the same objects with the new budget.
+    pub fn namespace(&self, name: &str, disk_budget: usize) -> Result<MultiLayerCache> {
+        if name.is_empty() || name.contains(Self::SEPARATOR) {
+            return Err(CreedmoorError::InvalidConfig(format!(
+                "namespace names must be non-empty and not contain '{}', got {:?}",
+                Self::SEPARATOR,
+                name
+            )));
+        }
+        let config = self.namespace_config(disk_budget)?;
+        let mut namespaces = self.global_budget.namespaces_mut();
+        let cache = match namespaces.get(name) {
+            // Handles to an open namespace share its stats and drift tracking
+            Some(open) => MultiLayerCache {
+                disk_budget,
+                max_object_size: config.max_object_size.unwrap_or(disk_budget),
+                ..open.clone()
+            },
+            None => {
+                let cache = MultiLayerCache::open(self.db.clone(), &config, Some(name))?;
+                namespaces.insert(name.to_string(), cache.clone());
+                cache
+            }
+        };
+        drop(namespaces);
+        Ok(MultiLayerCache { global_budget: Some(self.global_budget.clone()), ..cache })
+    }
+
+    /// The registry's configuration with the budget of one namespace.
+    fn namespace_config(&self, disk_budget: usize) -> Result<CacheConfig> {
+        let mut config = self.config.clone();
+        config.disk_budget = disk_budget;
+        config.max_object_size = config.max_object_size.map(|max_object_size| max_object_size.min(disk_budget));
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Names of the namespaces in the database.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.global_budget.namespaces().keys().cloned().collect()
+    }
+
+    /// Bytes charged across all namespaces.
+    pub fn disk_usage(&self) -> Result<usize> {
+        GlobalBudget::usage(self.global_budget.namespaces().values())
+    }
+
+    /// Evict from the namespaces until their combined usage is within the global cap. Puts do
+    /// this already; returns the number of bytes evicted.
+    pub fn enforce_global_budget(&self) -> Result<usize> {
+        self.global_budget.enforce()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{reopen, TestDir};
+    use crate::Delivery;
+
+    #[test]
+    fn test_namespaces() {
+        let registry = CacheRegistry::open(CacheConfig::new(100).temporary(true)).unwrap();
+        let thumbnails = registry.namespace("thumbnails", 60).unwrap();
+        let responses = registry.namespace("responses", 60).unwrap();
+        assert_eq!(registry.namespaces(), vec!["responses".to_string(), "thumbnails".to_string()]);
+
+        // The same key in two namespaces holds two objects
+        thumbnails.put(b"key", &[1; 30]).unwrap();
+        responses.put(b"key", &[2; 30]).unwrap();
+        assert_eq!(thumbnails.get(b"key").unwrap().unwrap(), [1; 30].as_slice());
+        assert_eq!(responses.get(b"key").unwrap().unwrap(), [2; 30].as_slice());
+
+        // Each namespace is held to its own budget
+        thumbnails.put(b"a", &[1; 30]).unwrap();
+        thumbnails.put(b"b", &[1; 30]).unwrap();
+        assert_eq!(thumbnails.logical_disk_usage().unwrap(), 60);
+        assert!(!thumbnails.contains_key(b"key").unwrap());
+        assert_eq!(responses.logical_disk_usage().unwrap(), 30);
+
+        // Together they are held to the global cap, evicting the oldest object of either
+        responses.put(b"c", &[2; 20]).unwrap();
+        assert_eq!(registry.disk_usage().unwrap(), 80);
+        assert!(!responses.contains_key(b"key").unwrap());
+        responses.put(b"d", &[2; 25]).unwrap();
+        assert_eq!(registry.disk_usage().unwrap(), 75);
+        assert!(!thumbnails.contains_key(b"a").unwrap());
+        assert!(thumbnails.contains_key(b"b").unwrap());
+        assert!(responses.contains_key(b"c").unwrap());
+
+        assert!(matches!(registry.namespace("", 10), Err(CreedmoorError::InvalidConfig(_))));
+        assert!(matches!(registry.namespace("a/b", 10), Err(CreedmoorError::InvalidConfig(_))));
+        assert!(matches!(registry.namespace("empty", 0), Err(CreedmoorError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_registry_reopen() {
+        let sled_path = TestDir::new();
+        let registry = CacheRegistry::open(CacheConfig::new(100).path(&sled_path)).unwrap();
+        registry.namespace("thumbnails", 100).unwrap().put(b"a", &[1; 60]).unwrap();
+        drop(registry);
+
+        // Namespaces left in the database still count towards the cap before being reopened
+        let registry = reopen(|| CacheRegistry::open(CacheConfig::new(100).path(&sled_path)));
+        assert_eq!(registry.namespaces(), vec!["thumbnails".to_string()]);
+        assert_eq!(registry.disk_usage().unwrap(), 60);
+        registry.namespace("responses", 100).unwrap().put(b"b", &[2; 60]).unwrap();
+        assert_eq!(registry.disk_usage().unwrap(), 60);
+        let thumbnails = registry.namespace("thumbnails", 100).unwrap();
+        assert!(!thumbnails.contains_key(b"a").unwrap());
+        drop((thumbnails, registry));
+    }
+
+    #[test]
+    fn test_registry_rejects_physical_budget() {
+        let config = CacheConfig::new(100)
+            .temporary(true)
+            .budget_enforcement(BudgetEnforcement::Both { check_interval: std::time::Duration::ZERO });
+        assert!(matches!(CacheRegistry::open(config), Err(CreedmoorError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_concurrent_puts() {
+        let registry = CacheRegistry::open(CacheConfig::new(1000).temporary(true)).unwrap();
+        let threads = ["a", "b", "c", "d"].map(|name| {
+            let cache = registry.namespace(name, 1000).unwrap();
+            std::thread::spawn(move || {
+                for n in 0..100u32 {
+                    cache.put(&n.to_be_bytes(), &[0; 20]).unwrap();
+                }
+            })
+        });
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // Puts that found another one evicting left the cap to it
+        assert!(registry.disk_usage().unwrap() <= 1000);
+    }
+
+    #[test]
+    fn test_listener_calls_registry() {
+        let registry = Arc::new(Mutex::new(None::<CacheRegistry>));
+        let usages = Arc::new(Mutex::new(Vec::new()));
+        let (handle, seen) = (registry.clone(), usages.clone());
+        let config = CacheConfig::new(50).temporary(true).removal_listener(
+            move |_| {
+                // Called while the global cap is enforced, so it must not find the registry locked
+                let registry = handle.lock().unwrap().clone().unwrap();
+                assert_eq!(registry.namespaces().len(), 2);
+                seen.lock().unwrap().push(registry.disk_usage().unwrap());
+            },
+            Delivery::Sync,
+        );
+        *registry.lock().unwrap() = Some(CacheRegistry::open(config).unwrap());
+        let opened = registry.lock().unwrap().clone().unwrap();
+        opened.namespace("thumbnails", 50).unwrap().put(b"a", &[1; 30]).unwrap();
+        opened.namespace("responses", 50).unwrap().put(b"b", &[2; 30]).unwrap();
+        assert_eq!(*usages.lock().unwrap(), vec![30]);
+        // The listener holds the registry, so let go of it to drop the database
+        registry.lock().unwrap().take();
+    }
+}
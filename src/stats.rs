@@ -0,0 +1,199 @@
+//! Hit, miss and eviction counters for a `MultiLayerCache`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sled::{Batch, Tree};
+
+use crate::{decode_u64, Result};
+
+/// Snapshot of a cache's counters, as returned by `MultiLayerCache::stats`.
+///
+/// The counters are cumulative since the cache was opened, or since it was created if
+/// `CacheConfig::persist_stats` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// `get` calls that found a live object
+    pub hits: u64,
+    /// `get` calls that found nothing, or an expired object
+    pub misses: u64,
+    /// Puts of a key that wasn't cached
+    pub inserts: u64,
+    /// Puts that replaced a cached object
+    pub overwrites: u64,
+    /// Objects evicted to stay within a budget
+    pub evictions: u64,
+    /// Bytes charged to the evicted objects, see `SizeModel`
+    pub evicted_bytes: u64,
+    /// Puts rejected with `CreedmoorError::CacheObjectSizeTooLarge`
+    pub rejected_oversize: u64,
+    /// Puts rejected with `CreedmoorError::NotAdmitted`
+    pub rejected_admission: u64,
+    /// Bytes currently charged against the disk budget
+    pub disk_usage: usize,
+    pub disk_budget: usize,
+}
+
+impl CacheStats {
+    /// Share of `get` calls that were hits, or zero before the first `get`.
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+/// The live counters behind `CacheStats`, shared by every clone of a cache.
+///
+/// With persistence on they are loaded from `tree` when the cache is opened and written back
+/// by `save` and when the last clone is dropped.
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+    pub(crate) inserts: AtomicU64,
+    pub(crate) overwrites: AtomicU64,
+    pub(crate) evictions: AtomicU64,
+    pub(crate) evicted_bytes: AtomicU64,
+    pub(crate) rejected_oversize: AtomicU64,
+    pub(crate) rejected_admission: AtomicU64,
+    tree: Option<Tree>,
+}
+
+impl StatsCounters {
+    /// Counters persisted in `tree`, starting from the values saved there.
+    pub(crate) fn load(tree: Tree) -> Result<Self> {
+        let mut counters = Self::default();
+        for (name, counter) in counters.named() {
+            if let Some(value) = tree.get(name)? {
+                counter.store(decode_u64(&value, "saved stats counter")?, Ordering::Relaxed);
+            }
+        }
+        counters.tree = Some(tree);
+        Ok(counters)
+    }
+
+    fn named(&self) -> [(&'static str, &AtomicU64); 8] {
+        [
+            ("hits", &self.hits),
+            ("misses", &self.misses),
+            ("inserts", &self.inserts),
+            ("overwrites", &self.overwrites),
+            ("evictions", &self.evictions),
+            ("evicted_bytes", &self.evicted_bytes),
+            ("rejected_oversize", &self.rejected_oversize),
+            ("rejected_admission", &self.rejected_admission),
+        ]
+    }
+
+    pub(crate) fn add(counter: &AtomicU64, value: usize) {
+        counter.fetch_add(value as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_evictions(&self, evictions: usize, evicted_bytes: usize) {
+        Self::add(&self.evictions, evictions);
+        Self::add(&self.evicted_bytes, evicted_bytes);
+    }
+
+    /// Write the counters to their tree in one batch and flush it, if they are persisted.
+    pub(crate) fn save(&self) -> Result<()> {
+        let Some(tree) = &self.tree else {
+            return Ok(());
+        };
+        let mut batch = Batch::default();
+        for (name, counter) in self.named() {
+            batch.insert(name, &counter.load(Ordering::Relaxed).to_be_bytes());
+        }
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn snapshot(&self, disk_usage: usize, disk_budget: usize) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            overwrites: self.overwrites.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            evicted_bytes: self.evicted_bytes.load(Ordering::Relaxed),
+            rejected_oversize: self.rejected_oversize.load(Ordering::Relaxed),
+            rejected_admission: self.rejected_admission.load(Ordering::Relaxed),
+            disk_usage,
+            disk_budget,
+        }
+    }
+}
+
+impl Drop for StatsCounters {
+    fn drop(&mut self) {
+        // Nowhere to report a failure from here; counts since the last `save` are lost
+        let _ = self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{reopen, TestDir};
+    use crate::{CacheConfig, CreedmoorError, MultiLayerCache};
+
+    #[test]
+    fn test_stats() {
+        let cache = MultiLayerCache::temporary(1024, 10).unwrap();
+        cache.put(b"a", b"12345").unwrap();
+        cache.put(b"a", b"1234").unwrap();
+        cache.put(b"b", b"12345").unwrap();
+        cache.put(b"c", b"12").unwrap();
+        assert!(matches!(cache.put(b"d", &[0; 11]), Err(CreedmoorError::CacheObjectSizeTooLarge(11))));
+        assert!(cache.get(b"b").unwrap().is_some());
+        assert!(cache.get(b"a").unwrap().is_none());
+        // Peeking doesn't count as a lookup
+        cache.peek(b"b").unwrap();
+        let stats = cache.stats().unwrap();
+        assert_eq!(
+            stats,
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                inserts: 3,
+                overwrites: 1,
+                evictions: 1,
+                evicted_bytes: 4,
+                rejected_oversize: 1,
+                rejected_admission: 0,
+                disk_usage: 7,
+                disk_budget: 10,
+            }
+        );
+        assert_eq!(stats.hit_ratio(), 0.5);
+    }
+
+    #[test]
+    fn test_persist_stats() {
+        let sled_path = TestDir::new();
+        let open = || reopen(|| CacheConfig::new(1024).path(&sled_path).persist_stats(true).build());
+        let cache = open();
+        cache.put(b"a", b"1").unwrap();
+        cache.get(b"a").unwrap();
+        let clone = cache.clone();
+        drop(cache);
+        clone.get(b"b").unwrap();
+        drop(clone);
+
+        let cache = open();
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.inserts, stats.hits, stats.misses), (1, 1, 1));
+        cache.get(b"a").unwrap();
+        cache.save_stats().unwrap();
+        drop(cache);
+
+        // Without persistence the counters start from zero
+        let cache = reopen(|| CacheConfig::new(1024).path(&sled_path).build());
+        assert_eq!(cache.stats().unwrap().hits, 0);
+        drop(cache);
+        assert_eq!(open().stats().unwrap().hits, 2);
+    }
+}
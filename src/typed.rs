@@ -0,0 +1,153 @@
+//! A `MultiLayerCache` that takes and returns typed keys and values.
+
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use get_size::GetSize;
+
+use crate::codec::Codec;
+use crate::{EntryInfo, MultiLayerCache, Result};
+
+/// Marks the key, value and codec types without owning any of them, so `TypedCache` is
+/// `Send` and `Sync` whatever they are.
+type Types<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+/// Wraps a `MultiLayerCache`, encoding keys and values with the codec `C`.
+///
+/// Objects are charged for their encoded bytes, like any other put.
+pub struct TypedCache<K, V, C> {
+    cache: MultiLayerCache,
+    _types: Types<K, V, C>,
+}
+
+impl<K, V, C> Clone for TypedCache<K, V, C> {
+    fn clone(&self) -> Self {
+        Self {
+            cache: self.cache.clone(),
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<K, V, C: Codec<K> + Codec<V>> TypedCache<K, V, C> {
+    pub fn new(cache: MultiLayerCache) -> Self {
+        Self {
+            cache,
+            _types: PhantomData,
+        }
+    }
+
+    /// The untyped cache underneath.
+    pub fn inner(&self) -> &MultiLayerCache {
+        &self.cache
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> Result<()> {
+        self.cache.put(&<C as Codec<K>>::encode(key)?, &<C as Codec<V>>::encode(value)?)
+    }
+
+    pub fn put_with_ttl(&self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        self.cache.put_with_ttl(&<C as Codec<K>>::encode(key)?, &<C as Codec<V>>::encode(value)?, ttl)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.decode(self.cache.get(&<C as Codec<K>>::encode(key)?)?)
+    }
+
+    pub fn peek(&self, key: &K) -> Result<Option<V>> {
+        self.decode(self.cache.peek(&<C as Codec<K>>::encode(key)?)?)
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        self.cache.contains_key(&<C as Codec<K>>::encode(key)?)
+    }
+
+    pub fn entry_info(&self, key: &K) -> Result<Option<EntryInfo>> {
+        self.cache.entry_info(&<C as Codec<K>>::encode(key)?)
+    }
+
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        self.decode(self.cache.remove(&<C as Codec<K>>::encode(key)?)?)
+    }
+
+    /// Estimate what storing `value` under `key` would be charged, before paying for encoding.
+    ///
+    /// Uses the in-memory size of the key and value from `GetSize` in place of their encoded
+    /// lengths, so it is only a guide: compact codecs usually come in under it.
+    pub fn estimate_charge(&self, key: &K, value: &V) -> usize
+    where
+        K: GetSize,
+        V: GetSize,
+    {
+        self.cache.size_model.charge_lengths(key.get_size(), value.get_size())
+    }
+
+    fn decode(&self, bytes: Option<sled::IVec>) -> Result<Option<V>> {
+        bytes.map(|bytes| <C as Codec<V>>::decode(&bytes)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Raw;
+
+    #[test]
+    fn test_raw_typed_cache() {
+        let cache = MultiLayerCache::temporary(1024, 1024).unwrap();
+        let typed: TypedCache<String, Vec<u8>, Raw> = TypedCache::new(cache);
+        typed.put(&"key".to_string(), &vec![1, 2, 3]).unwrap();
+        assert_eq!(typed.get(&"key".to_string()).unwrap(), Some(vec![1, 2, 3]));
+        assert!(typed.contains_key(&"key".to_string()).unwrap());
+        // Charged for the encoded bytes
+        assert_eq!(typed.entry_info(&"key".to_string()).unwrap().unwrap().size, 3);
+        assert_eq!(typed.remove(&"key".to_string()).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(typed.get(&"key".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_estimate_charge() {
+        let cache = MultiLayerCache::temporary(1024, 1024).unwrap();
+        let typed: TypedCache<String, String, Raw> = TypedCache::new(cache);
+        let value = "x".repeat(100);
+        // The estimate covers the heap bytes, plus the `String` header
+        assert_eq!(typed.estimate_charge(&"key".to_string(), &value), value.get_size());
+        assert!(typed.estimate_charge(&"key".to_string(), &value) >= 100);
+    }
+
+    #[cfg(any(feature = "bincode", feature = "json", feature = "postcard"))]
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Response {
+        status: u16,
+        body: String,
+    }
+
+    #[cfg(any(feature = "bincode", feature = "json", feature = "postcard"))]
+    fn round_trip<C: Codec<u64> + Codec<Response>>() {
+        let cache = MultiLayerCache::temporary(1024, 1024).unwrap();
+        let typed: TypedCache<u64, Response, C> = TypedCache::new(cache);
+        let response = Response { status: 200, body: "ok".to_string() };
+        typed.put(&7, &response).unwrap();
+        assert_eq!(typed.get(&7).unwrap(), Some(response.clone()));
+        let encoded = <C as Codec<Response>>::encode(&response).unwrap();
+        assert_eq!(typed.entry_info(&7).unwrap().unwrap().size, encoded.len());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_typed_cache() {
+        round_trip::<crate::codec::Bincode>();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_typed_cache() {
+        round_trip::<crate::codec::Json>();
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_postcard_typed_cache() {
+        round_trip::<crate::codec::Postcard>();
+    }
+}